@@ -0,0 +1,141 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use error_handler::Error;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, Instant};
+
+/// Time to wait for an attempt before starting the next one in parallel,
+/// the value recommended by RFC 8305 section 8.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Orders resolved addresses for connection attempts as in RFC 8305
+/// section 4: IPv6 first, then alternating between the two families while
+/// keeping the resolver's order within each family.
+pub fn interleave_addresses(addresses: &[IpAddr]) -> Vec<IpAddr> {
+    let (mut ipv6, mut ipv4): (Vec<IpAddr>, Vec<IpAddr>) = (Vec::new(), Vec::new());
+    for address in addresses {
+        let family = if address.is_ipv6() { &mut ipv6 } else { &mut ipv4 };
+        if !family.contains(address) {
+            family.push(*address);
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(ipv6.len() + ipv4.len());
+    let (mut ipv6, mut ipv4) = (ipv6.into_iter(), ipv4.into_iter());
+    loop {
+        match (ipv6.next(), ipv4.next()) {
+            (None, None) => break,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+    ordered
+}
+
+/// Connects to the first of `addresses` that answers.
+///
+/// Attempts are started in order, each one `attempt_delay` after the
+/// previous, or immediately when the previous one fails. The first
+/// established connection wins and the others are cancelled.
+pub async fn connect(addresses: &[SocketAddr], attempt_delay: Duration) -> Result<TcpStream, Error> {
+    let mut remaining = addresses.iter().copied().peekable();
+    let mut attempts = JoinSet::new();
+
+    let Some(first) = remaining.next() else {
+        return Err(Error::AsyncStream("No address to connect to".to_string()));
+    };
+    attempts.spawn(TcpStream::connect(first));
+    let mut next_attempt = Instant::now() + attempt_delay;
+
+    loop {
+        tokio::select! {
+            Some(result) = attempts.join_next() => {
+                let error = match result {
+                    // Dropping the set aborts the attempts still in flight.
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(err)) => err,
+                    Err(err) => err.into(),
+                };
+
+                if let Some(address) = remaining.next() {
+                    attempts.spawn(TcpStream::connect(address));
+                    next_attempt = Instant::now() + attempt_delay;
+                } else if attempts.is_empty() {
+                    return Err(Error::from(error));
+                }
+            },
+            _ = sleep_until(next_attempt), if remaining.peek().is_some() => {
+                if let Some(address) = remaining.next() {
+                    attempts.spawn(TcpStream::connect(address));
+                }
+                next_attempt = Instant::now() + attempt_delay;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    /// A local port nothing listens on, so connecting to it is refused at once.
+    async fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn test_interleave_addresses() {
+        let addresses = [ip("192.0.2.1"), ip("192.0.2.2"), ip("192.0.2.3"), ip("2001:db8::1"), ip("2001:db8::2"), ip("192.0.2.1")];
+        assert_eq!(interleave_addresses(&addresses), vec![
+            ip("2001:db8::1"), ip("192.0.2.1"), ip("2001:db8::2"), ip("192.0.2.2"), ip("192.0.2.3"),
+        ]);
+
+        assert_eq!(interleave_addresses(&[ip("192.0.2.1")]), vec![ip("192.0.2.1")]);
+        assert!(interleave_addresses(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refused_address_falls_through_to_next() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening = listener.local_addr().unwrap();
+
+        let stream = connect(&[closed_port().await, listening], Duration::from_secs(60)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_address_is_raced() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening = listener.local_addr().unwrap();
+        // A TEST-NET-1 address: the attempt either hangs or fails, and the
+        // second address must win either way.
+        let stalled = SocketAddr::new(ip("192.0.2.1"), 25);
+
+        let stream = connect(&[stalled, listening], Duration::from_millis(50)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_loopback() {
+        let Ok(listener) = TcpListener::bind("[::1]:0").await else { return; };
+        let listening = listener.local_addr().unwrap();
+
+        let stream = connect(&[listening, closed_port().await], CONNECTION_ATTEMPT_DELAY).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening);
+    }
+
+    #[tokio::test]
+    async fn test_all_addresses_fail() {
+        let result = connect(&[closed_port().await, closed_port().await], CONNECTION_ATTEMPT_DELAY).await;
+        assert!(matches!(result, Err(Error::Io(_))));
+
+        assert!(connect(&[], CONNECTION_ATTEMPT_DELAY).await.is_err());
+    }
+}
@@ -2,11 +2,23 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 use error_handler::Error;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncWrite, AsyncRead, ReadBuf};
-use tokio_native_tls::{native_tls::TlsConnector as NativeTlsConnector, TlsConnector, TlsStream};
+use tokio::io::{AsyncWriteExt, AsyncWrite, AsyncRead, ReadBuf};
+use tokio_native_tls::TlsStream;
 
 use tokio::net::{TcpStream, lookup_host};
-use std::net::{SocketAddr, Ipv4Addr};
+use std::net::{IpAddr, SocketAddr};
+
+mod happy_eyeballs;
+mod public_ip;
+mod reply_reader;
+mod tls_config;
+#[cfg(any(test, feature = "test-pki"))]
+pub mod test_pki;
+
+pub use happy_eyeballs::CONNECTION_ATTEMPT_DELAY;
+pub use public_ip::{HttpPublicIpResolver, PublicIpResolver};
+pub use reply_reader::ReplyReader;
+pub use tls_config::TlsConfig;
 
 pub enum StreamIo<T: AsyncRead + AsyncWrite + Unpin> {
     Plain(T),
@@ -68,32 +80,71 @@ pub enum NodeType {
     Peer,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct NodeInfo {
     m_node_type: NodeType,
-    m_ipv4: Ipv4Addr,
+    m_hostname: String,
+    m_ip: IpAddr,
+    m_addresses: Vec<IpAddr>,
     m_port: u16,
 }
 
 impl NodeInfo {
     pub async fn new(node_type: NodeType, host: &str) -> Result<Self, Error> {
-        let result_addrs = lookup_host(host).await?;
-        for addr in result_addrs {
-            if let SocketAddr::V4(v4) = addr {
-                return Ok(
-                    Self {
-                        m_node_type: node_type,
-                        m_ipv4: *v4.ip(),
-                        m_port: v4.port(),
-                    }
-                )
-            }
+        let hostname = Self::parse_hostname(host)
+            .ok_or_else(|| Error::AsyncStream("Invalid address".to_string() + " " + host))?;
+
+        let mut port = 0;
+        let mut resolved = Vec::new();
+        for addr in lookup_host(host).await? {
+            port = addr.port();
+            resolved.push(addr.ip());
+        }
+
+        let addresses = happy_eyeballs::interleave_addresses(&resolved);
+        match addresses.first() {
+            Some(ip) => Ok(
+                Self {
+                    m_node_type: node_type,
+                    m_hostname: hostname,
+                    m_ip: *ip,
+                    m_addresses: addresses,
+                    m_port: port,
+                }
+            ),
+            None => Err(Error::AsyncStream("Invalid address".to_string() + " " + host)),
+        }
+    }
+
+    /// Extracts the name part of a `host:port` string, without the brackets
+    /// of an IPv6 literal.
+    fn parse_hostname(host: &str) -> Option<String> {
+        let (hostname, _port) = host.rsplit_once(':')?;
+        let hostname = hostname.strip_prefix('[')
+            .and_then(|hostname| hostname.strip_suffix(']'))
+            .unwrap_or(hostname);
+
+        if hostname.is_empty() {
+            None
+        } else {
+            Some(hostname.to_string())
         }
-        Err(Error::AsyncStream("Invalid address".to_string() + " " + host))
     }
 
-    pub fn get_ipv4(&self) -> Ipv4Addr {
-        self.m_ipv4
+    /// The name the user asked for, used for TLS SNI and certificate matching.
+    pub fn get_hostname(&self) -> &str {
+        &self.m_hostname
+    }
+
+    /// The address in use: the one that won the connection race once
+    /// connected, the first one to try before that.
+    pub fn get_ip(&self) -> IpAddr {
+        self.m_ip
+    }
+
+    /// Every resolved address, in the order connections are attempted.
+    pub fn get_addresses(&self) -> &[IpAddr] {
+        &self.m_addresses
     }
 
     pub fn get_port(&self) -> u16 {
@@ -105,15 +156,17 @@ impl NodeInfo {
     }
 
     pub fn get_connection_string(&self) -> String {
-        format!("{}:{}", self.m_ipv4, self.m_port)
+        SocketAddr::new(self.m_ip, self.m_port).to_string()
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 struct StreamInfo {
     m_is_encrypted: bool,
     m_host: NodeInfo,
-    m_peer: NodeInfo,
+    m_local_addr: SocketAddr,
+    m_peer_addr: SocketAddr,
+    m_public_ip: Option<IpAddr>,
 }
 
 impl StreamInfo {
@@ -121,84 +174,142 @@ impl StreamInfo {
         self.m_is_encrypted
     }
 
-    pub fn get_host(&mut self) -> &mut NodeInfo {
-        &mut self.m_host
+    pub fn get_host(&self) -> &NodeInfo {
+        &self.m_host
+    }
+
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.m_local_addr
+    }
+
+    pub fn get_peer_addr(&self) -> SocketAddr {
+        self.m_peer_addr
     }
 
-    pub fn get_peer(&self) -> &NodeInfo {
-        &self.m_peer
+    pub fn get_public_ip(&self) -> Option<IpAddr> {
+        self.m_public_ip
     }
 }
 
 pub struct AsyncStream {
     m_stream: Option<StreamIo<TcpStream>>,
     m_stream_info: Option<StreamInfo>,
+    m_reply_reader: ReplyReader,
     m_buffsize: u16,
 }
 
 impl AsyncStream {
+    /// Connects to `server`, racing its resolved IPv6 and IPv4 addresses as
+    /// described in RFC 8305. The winning address is reported by
+    /// `get_peer_addr` and by the host info.
     pub async fn new(server: &str) -> Result<Self, Error> {
-        let host = NodeInfo::new(Host, server).await?;
-        let peer = NodeInfo::new(Peer, "api.ipify.org:80").await?;
-
-        let stream = TcpStream::connect(server).await?;
+        let mut host = NodeInfo::new(Host, server).await?;
+
+        let addresses: Vec<SocketAddr> = host.get_addresses().iter()
+            .map(|ip| SocketAddr::new(*ip, host.get_port()))
+            .collect();
+        let stream = happy_eyeballs::connect(&addresses, CONNECTION_ATTEMPT_DELAY).await?;
+        let local_addr = stream.local_addr()?;
+        let peer_addr = stream.peer_addr()?;
+        host.m_ip = peer_addr.ip();
         Ok(
             Self {
                 m_stream: Some(StreamIo::Plain(stream)),
+                m_reply_reader: ReplyReader::new(),
                 m_buffsize: 1024,
                 m_stream_info: Some(
                     StreamInfo {
                         m_is_encrypted: false,
                         m_host: host,
-                        m_peer: peer,
+                        m_local_addr: local_addr,
+                        m_peer_addr: peer_addr,
+                        m_public_ip: None,
                     }
                 ), 
             }
         )
     }
 
-    pub async fn try_upgrade_to_tls(&mut self) -> Result<(), Error> {
+    /// Opens a connection that is encrypted from the first byte, as used by
+    /// SMTP submission over implicit TLS on port 465 (RFC 8314).
+    pub async fn new_encrypted(server: &str, tls_config: &TlsConfig) -> Result<Self, Error> {
+        let mut stream = Self::new(server).await?;
+        stream.try_upgrade_to_tls(tls_config).await?;
+        Ok(stream)
+    }
+
+    pub async fn try_upgrade_to_tls(&mut self, tls_config: &TlsConfig) -> Result<(), Error> {
         if !self.is_open() {
             return Err(Error::ClosedConnection("Encrypt connection".to_string()));
         }
 
-        let native_tls_connector = NativeTlsConnector::builder()
-            .danger_accept_invalid_certs(true)
-            .build()?;
-
-        let tls_connector = TlsConnector::from(native_tls_connector);
-
-        if let Some(StreamIo::Plain(stream)) = self.m_stream.take() {
-            if self.m_stream_info.is_some() {
-                let host = *self.m_stream_info.unwrap().get_host();
-                let tls_stream = tls_connector.connect(&host.get_connection_string(), stream).await?;
-                self.m_stream = Some(StreamIo::Encrypted(tls_stream));
-                self.m_stream_info.unwrap().m_is_encrypted = true;
-                Ok(())
-            } else {
-                Err(Error::TlsUpgrade("Encrypt connection. Connection is already encrypted".to_string()))
-            }
-        } else {
-            Err(Error::TlsUpgrade("Encrypt connection. Connection is already encrypted".to_string()))
+        if !matches!(self.m_stream, Some(StreamIo::Plain(_))) {
+            return Err(Error::TlsUpgrade("Encrypt connection. Connection is already encrypted".to_string()));
+        }
+
+        // Anything the server sent after its STARTTLS reply would be trusted as if it
+        // had arrived over TLS, so such a stream is refused.
+        if self.m_reply_reader.has_buffered_data() {
+            return Err(Error::TlsUpgrade("Encrypt connection. Unexpected data after STARTTLS reply".to_string()));
         }
+
+        let tls_connector = tls_config.build_connector()?;
+
+        let (Some(StreamIo::Plain(stream)), Some(stream_info)) = (self.m_stream.take(), self.m_stream_info.as_mut()) else {
+            return Err(Error::ClosedConnection("Encrypt connection".to_string()));
+        };
+
+        let tls_stream = tls_connector.connect(stream_info.get_host().get_hostname(), stream).await?;
+        self.m_stream = Some(StreamIo::Encrypted(tls_stream));
+        stream_info.m_is_encrypted = true;
+        Ok(())
     }
 
     pub fn get_host_info(&self) -> Result<NodeInfo, Error> {
-        if self.m_stream_info.is_some() {
-            Ok(*self.m_stream_info.unwrap().get_host())
+        if let Some(stream_info) = self.m_stream_info.as_ref() {
+            Ok(stream_info.get_host().clone())
         } else {
             Err(Error::ClosedConnection("Get host info".to_string()))
         }
     }
 
-    pub fn get_peer_info(&self) -> Result<NodeInfo, Error> {
-        if self.m_stream_info.is_some() {
-            Ok(*self.m_stream_info.unwrap().get_peer())
+    /// The local end of the TCP connection.
+    pub fn get_local_addr(&self) -> Result<SocketAddr, Error> {
+        if let Some(stream_info) = self.m_stream_info.as_ref() {
+            Ok(stream_info.get_local_addr())
         } else {
-            Err(Error::ClosedConnection("Get peer info".to_string()))
+            Err(Error::ClosedConnection("Get local address".to_string()))
         }
     }
 
+    /// The server end of the TCP connection.
+    pub fn get_peer_addr(&self) -> Result<SocketAddr, Error> {
+        if let Some(stream_info) = self.m_stream_info.as_ref() {
+            Ok(stream_info.get_peer_addr())
+        } else {
+            Err(Error::ClosedConnection("Get peer address".to_string()))
+        }
+    }
+
+    /// Looks up the public address of this client with `resolver` and keeps
+    /// it for `get_public_ip`.
+    pub async fn resolve_public_ip(&mut self, resolver: &dyn PublicIpResolver) -> Result<IpAddr, Error> {
+        if !self.is_open() {
+            return Err(Error::ClosedConnection("Resolve public IP".to_string()));
+        }
+
+        let public_ip = resolver.resolve().await?;
+        if let Some(stream_info) = self.m_stream_info.as_mut() {
+            stream_info.m_public_ip = Some(public_ip);
+        }
+        Ok(public_ip)
+    }
+
+    /// The public address found by `resolve_public_ip`, if it was called.
+    pub fn get_public_ip(&self) -> Option<IpAddr> {
+        self.m_stream_info.as_ref().and_then(StreamInfo::get_public_ip)
+    }
+
     pub fn is_open(&self) -> bool {
         self.m_stream.is_some()
     }
@@ -209,51 +320,117 @@ impl AsyncStream {
     }
 
     pub fn is_encrypted(&self) -> Result<bool, Error> {
-        if self.m_stream_info.is_some() {
-            Ok(self.m_stream_info.unwrap().is_encrypted())
+        if let Some(stream_info) = self.m_stream_info.as_ref() {
+            Ok(stream_info.is_encrypted())
         } else {
             Err(Error::ClosedConnection("Check encryption status".to_string()))
         }
     }
 
+    /// Writes the whole buffer and returns its length.
     pub async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         if self.is_open() {
-            self.m_stream.as_mut().unwrap().write(buf).await.map_err(Error::from)
+            self.m_stream.as_mut().unwrap().write_all(buf).await?;
+            Ok(buf.len())
         } else {
             Err(Error::ClosedConnection("Write".to_string()))
         }
     }
 
+    /// Reads one complete, possibly multi-line, SMTP reply.
     pub async fn read(&mut self) -> Result<String, Error> {
-        if self.is_open() {
-            self.read_response_until_crlf().await
-        } else {
-            Err(Error::ClosedConnection("Read".to_string()))
+        match self.m_stream.as_mut() {
+            Some(stream) => self.m_reply_reader.read_reply(stream, self.m_buffsize as usize).await,
+            None => Err(Error::ClosedConnection("Read".to_string())),
         }
     }
+}
 
-    async fn read_response_until_crlf(&mut self) -> Result<String, Error> {
-        let mut response = String::new();
-        let mut buffer: Vec<u8> = vec![0; self.m_buffsize as usize];
-        
-        loop {
-            if !self.is_open() {
-                break;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_node_info_keeps_hostname() {
+        let node = NodeInfo::new(Host, "localhost:2525").await.unwrap();
+        assert_eq!(node.get_hostname(), "localhost");
+        assert!(node.get_addresses().contains(&IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(node.get_ip(), node.get_addresses()[0]);
+        assert_eq!(node.get_port(), 2525);
+    }
+
+    #[tokio::test]
+    async fn test_node_info_ipv6_literal() {
+        let node = NodeInfo::new(Host, "[::1]:2525").await.unwrap();
+        assert_eq!(node.get_hostname(), "::1");
+        assert_eq!(node.get_ip(), IpAddr::from(std::net::Ipv6Addr::LOCALHOST));
+        assert_eq!(node.get_connection_string(), "[::1]:2525");
+    }
 
-            let bytes_read = self.m_stream.as_mut().unwrap().read(&mut buffer).await?;
+    #[tokio::test]
+    async fn test_new_reports_socket_addresses() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { listener.accept().await.unwrap().1 });
 
-            if bytes_read == 0 {
-                break;
-            }
-            
-            let chunk = &buffer[..bytes_read];
-            response.push_str(&String::from_utf8_lossy(chunk));
+        let stream = AsyncStream::new(&server_addr.to_string()).await.unwrap();
+        let client_addr = server.await.unwrap();
+
+        assert_eq!(stream.get_peer_addr(), Ok(server_addr));
+        assert_eq!(stream.get_local_addr(), Ok(client_addr));
+        assert_eq!(stream.get_host_info().unwrap().get_connection_string(), server_addr.to_string());
+        assert_eq!(stream.get_public_ip(), None);
+        assert_eq!(stream.is_encrypted(), Ok(false));
+    }
+
+    #[test]
+    fn test_parse_hostname() {
+        assert_eq!(NodeInfo::parse_hostname("smtp.gmail.com:587"), Some("smtp.gmail.com".to_string()));
+        assert_eq!(NodeInfo::parse_hostname("127.0.0.1:25"), Some("127.0.0.1".to_string()));
+        assert_eq!(NodeInfo::parse_hostname("[::1]:25"), Some("::1".to_string()));
+        assert_eq!(NodeInfo::parse_hostname(":25"), None);
+        assert_eq!(NodeInfo::parse_hostname("smtp.gmail.com"), None);
+    }
 
-            if response.ends_with("\r\n") {
-                break;
+    /// Accepts one TLS connection with the test certificate and greets the
+    /// client once the handshake succeeded. Returns the port listened on.
+    async fn tls_server(pki: &test_pki::TestPki) -> (u16, tokio::task::JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = pki.acceptor();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(mut tls_stream) = acceptor.accept(stream).await {
+                let _ = tls_stream.write_all(b"220 ready\r\n").await;
             }
-        }
-        Ok(response)
+        });
+
+        (port, server)
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_verifies_the_server_hostname() {
+        // The certificate names "localhost" but not 127.0.0.1, which the
+        // connection actually goes to.
+        let pki = test_pki::TestPki::localhost_only();
+        let (port, server) = tls_server(&pki).await;
+        let config = TlsConfig::default().add_root_certificate_file(pki.get_ca_file());
+
+        let mut stream = AsyncStream::new(&format!("localhost:{}", port)).await.unwrap();
+        assert_eq!(stream.try_upgrade_to_tls(&config).await, Ok(()));
+        assert_eq!(stream.is_encrypted(), Ok(true));
+        assert_eq!(stream.read().await, Ok("220 ready\r\n".to_string()));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_rejects_untrusted_certificate() {
+        let pki = test_pki::TestPki::new();
+        let (port, server) = tls_server(&pki).await;
+
+        let result = AsyncStream::new_encrypted(&format!("localhost:{}", port), &TlsConfig::default()).await;
+        assert!(result.is_err());
+        server.await.unwrap();
     }
 }
@@ -0,0 +1,96 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use error_handler::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Looks up the address the client is seen from on the public internet.
+///
+/// Nothing in `AsyncStream` calls a resolver on its own; lookups only happen
+/// through `AsyncStream::resolve_public_ip`.
+pub trait PublicIpResolver: Send + Sync {
+    fn resolve(&self) -> Pin<Box<dyn Future<Output = Result<IpAddr, Error>> + Send + '_>>;
+}
+
+/// Asks a plain-text "what is my IP" HTTP service, `api.ipify.org` by default.
+#[derive(Clone, Debug)]
+pub struct HttpPublicIpResolver {
+    m_server: String,
+    m_host_header: String,
+}
+
+impl Default for HttpPublicIpResolver {
+    fn default() -> Self {
+        Self::new("api.ipify.org:80", "api.ipify.org")
+    }
+}
+
+impl HttpPublicIpResolver {
+    pub fn new(server: &str, host_header: &str) -> Self {
+        Self {
+            m_server: server.to_string(),
+            m_host_header: host_header.to_string(),
+        }
+    }
+
+    async fn fetch(&self) -> Result<IpAddr, Error> {
+        let mut stream = TcpStream::connect(&self.m_server).await?;
+        let request = format!("GET / HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", self.m_host_header);
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        Self::parse_response(&String::from_utf8_lossy(&response))
+    }
+
+    fn parse_response(response: &str) -> Result<IpAddr, Error> {
+        let (head, body) = response.split_once("\r\n\r\n")
+            .ok_or_else(|| Error::AsyncStream("Public IP lookup: malformed HTTP response".to_string()))?;
+
+        if head.split_whitespace().nth(1) != Some("200") {
+            return Err(Error::AsyncStream(format!("Public IP lookup: {}", head.lines().next().unwrap_or_default())));
+        }
+
+        Ok(body.trim().parse()?)
+    }
+}
+
+impl PublicIpResolver for HttpPublicIpResolver {
+    fn resolve(&self) -> Pin<Box<dyn Future<Output = Result<IpAddr, Error>> + Send + '_>> {
+        Box::pin(self.fetch())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_resolve_from_local_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let read = stream.read(&mut request).await.unwrap();
+            assert!(String::from_utf8_lossy(&request[..read]).contains("Host: ip.example.com\r\n"));
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n203.0.113.7").await.unwrap();
+        });
+
+        let resolver = HttpPublicIpResolver::new(&address.to_string(), "ip.example.com");
+        assert_eq!(resolver.resolve().await, Ok("203.0.113.7".parse().unwrap()));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(HttpPublicIpResolver::parse_response("HTTP/1.1 200 OK\r\n\r\n2001:db8::1\n"), Ok("2001:db8::1".parse().unwrap()));
+        assert!(HttpPublicIpResolver::parse_response("HTTP/1.1 503 Service Unavailable\r\n\r\n").is_err());
+        assert!(HttpPublicIpResolver::parse_response("HTTP/1.1 200 OK\r\n\r\nnot an ip").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use error_handler::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Splits an incoming byte stream into complete SMTP replies.
+///
+/// A reply ends with its first line whose code is not followed by `-`
+/// (RFC 5321 section 4.2.1). Bytes received past the end of a reply are kept
+/// for the next call, so replies split across or packed into TCP reads are
+/// framed correctly.
+#[derive(Default)]
+pub struct ReplyReader {
+    m_buffer: Vec<u8>,
+}
+
+impl ReplyReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn has_buffered_data(&self) -> bool {
+        !self.m_buffer.is_empty()
+    }
+
+    pub async fn read_reply<R: AsyncRead + Unpin>(&mut self, reader: &mut R, buffsize: usize) -> Result<String, Error> {
+        let mut buffer: Vec<u8> = vec![0; buffsize.max(1)];
+
+        loop {
+            if let Some(reply) = self.take_reply() {
+                return Ok(reply);
+            }
+
+            let bytes_read = reader.read(&mut buffer).await?;
+
+            if bytes_read == 0 {
+                return if self.m_buffer.is_empty() {
+                    Err(Error::ClosedConnection("Read".to_string()))
+                } else {
+                    Err(Error::ClosedConnection("Read the rest of a reply".to_string()))
+                };
+            }
+
+            self.m_buffer.extend_from_slice(&buffer[..bytes_read]);
+        }
+    }
+
+    fn take_reply(&mut self) -> Option<String> {
+        let mut line_start = 0;
+
+        while let Some(offset) = self.m_buffer[line_start..].iter().position(|byte| *byte == b'\n') {
+            let line_end = line_start + offset + 1;
+
+            if !Self::is_continuation_line(&self.m_buffer[line_start..line_end]) {
+                let reply: Vec<u8> = self.m_buffer.drain(..line_end).collect();
+                return Some(String::from_utf8_lossy(&reply).into_owned());
+            }
+
+            line_start = line_end;
+        }
+
+        None
+    }
+
+    fn is_continuation_line(line: &[u8]) -> bool {
+        line.len() > 3 && line[3] == b'-'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    async fn read_replies_from_chunks(chunks: &[&[u8]], buffsize: usize, count: usize) -> Vec<String> {
+        let (mut client, mut server) = duplex(64);
+        let chunks: Vec<Vec<u8>> = chunks.iter().map(|chunk| chunk.to_vec()).collect();
+
+        let writer = tokio::spawn(async move {
+            for chunk in chunks {
+                server.write_all(&chunk).await.unwrap();
+                server.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            server
+        });
+
+        let mut reader = ReplyReader::new();
+        let mut replies = Vec::new();
+        for _ in 0..count {
+            replies.push(reader.read_reply(&mut client, buffsize).await.unwrap());
+        }
+        assert!(!reader.has_buffered_data());
+
+        writer.await.unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn test_multi_line_reply_split_inside_code() {
+        let replies = read_replies_from_chunks(&[
+            b"250-smtp.example.com\r\n25",
+            b"0-SIZE 1000\r",
+            b"\n250",
+            b" PIPELINING\r\n",
+        ], 1024, 1).await;
+
+        assert_eq!(replies, vec!["250-smtp.example.com\r\n250-SIZE 1000\r\n250 PIPELINING\r\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_multi_line_reply_read_byte_by_byte() {
+        let reply = "250-smtp.example.com\r\n250-8BITMIME\r\n250 OK\r\n";
+        let replies = read_replies_from_chunks(&[reply.as_bytes()], 1, 1).await;
+
+        assert_eq!(replies, vec![reply]);
+    }
+
+    #[tokio::test]
+    async fn test_split_right_after_continuation_dash() {
+        let replies = read_replies_from_chunks(&[
+            b"250-",
+            b"first\r\n250-",
+            b"second\r\n250 last\r\n",
+        ], 3, 1).await;
+
+        assert_eq!(replies, vec!["250-first\r\n250-second\r\n250 last\r\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_two_replies_in_one_chunk() {
+        let replies = read_replies_from_chunks(&[
+            b"250 2.1.0 Ok\r\n250-2.1.5 Ok\r\n250 2.1.5 Ok\r\n354 End data with <CR><LF>.<CR><LF>\r\n",
+        ], 1024, 3).await;
+
+        assert_eq!(replies, vec![
+            "250 2.1.0 Ok\r\n",
+            "250-2.1.5 Ok\r\n250 2.1.5 Ok\r\n",
+            "354 End data with <CR><LF>.<CR><LF>\r\n",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_split_inside_multibyte_character() {
+        let reply = "250 Привіт\r\n".as_bytes();
+        let replies = read_replies_from_chunks(&[&reply[..6], &reply[6..]], 1024, 1).await;
+
+        assert_eq!(replies, vec!["250 Привіт\r\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_mid_reply() {
+        let (mut client, mut server) = duplex(64);
+        server.write_all(b"250-smtp.example.com\r\n250-SIZE").await.unwrap();
+        drop(server);
+
+        let mut reader = ReplyReader::new();
+        let result = reader.read_reply(&mut client, 1024).await;
+        assert_eq!(result, Err(Error::ClosedConnection("Read the rest of a reply".to_string())));
+        assert!(result.unwrap_err().is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_between_replies() {
+        let (mut client, server) = duplex(64);
+        drop(server);
+
+        let mut reader = ReplyReader::new();
+        let result = reader.read_reply(&mut client, 1024).await;
+        assert_eq!(result, Err(Error::ClosedConnection("Read".to_string())));
+    }
+}
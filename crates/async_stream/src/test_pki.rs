@@ -0,0 +1,119 @@
+//! A throwaway CA and a server certificate it signed, for tests that run a
+//! local TLS server. Enabled for other crates with the `test-pki` feature.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectAlternativeName};
+use openssl::x509::{X509, X509NameBuilder};
+use tokio_native_tls::native_tls::{Identity, TlsAcceptor as NativeTlsAcceptor};
+use tokio_native_tls::TlsAcceptor;
+
+fn generate_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+fn generate_certificate(common_name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>, san_ip: Option<&str>) -> X509 {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+    let name = name.build();
+
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+
+    match issuer {
+        Some((issuer_certificate, issuer_key)) => {
+            builder.set_issuer_name(issuer_certificate.subject_name()).unwrap();
+            builder.append_extension(BasicConstraints::new().build().unwrap()).unwrap();
+            let mut san = SubjectAlternativeName::new();
+            san.dns("localhost");
+            if let Some(ip) = san_ip {
+                san.ip(ip);
+            }
+            let san = san.build(&builder.x509v3_context(Some(issuer_certificate), None)).unwrap();
+            builder.append_extension(san).unwrap();
+            builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        },
+        None => {
+            builder.set_issuer_name(&name).unwrap();
+            builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+            builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build().unwrap()).unwrap();
+            builder.sign(key, MessageDigest::sha256()).unwrap();
+        },
+    }
+
+    builder.build()
+}
+
+/// The CA certificate is written to a temporary PEM file, removed on drop.
+pub struct TestPki {
+    m_ca_file: PathBuf,
+    m_identity: Identity,
+}
+
+impl TestPki {
+    /// A server certificate valid for "localhost" and 127.0.0.1.
+    pub fn new() -> Self {
+        Self::generate(Some("127.0.0.1"))
+    }
+
+    /// A server certificate valid for "localhost" only, so verification
+    /// fails when the client checks it against an IP address.
+    pub fn localhost_only() -> Self {
+        Self::generate(None)
+    }
+
+    fn generate(san_ip: Option<&str>) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let ca_key = generate_key();
+        let ca = generate_certificate("Test CA", &ca_key, None, None);
+        let server_key = generate_key();
+        let server = generate_certificate("localhost", &server_key, Some((&ca, &ca_key)), san_ip);
+
+        let ca_file = std::env::temp_dir().join(format!("async_stream_test_ca_{}_{}.pem",
+            std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        std::fs::write(&ca_file, ca.to_pem().unwrap()).unwrap();
+
+        let identity = Identity::from_pkcs8(&server.to_pem().unwrap(), &server_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        Self { m_ca_file: ca_file, m_identity: identity }
+    }
+
+    /// The PEM file to pass to `TlsConfig::add_root_certificate_file`.
+    pub fn get_ca_file(&self) -> &Path {
+        &self.m_ca_file
+    }
+
+    /// An acceptor presenting the server certificate.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(NativeTlsAcceptor::new(self.m_identity.clone()).unwrap())
+    }
+}
+
+impl Default for TestPki {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestPki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.m_ca_file);
+    }
+}
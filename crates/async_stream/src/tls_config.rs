@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+
+use error_handler::Error;
+use tokio_native_tls::{native_tls::{Certificate, TlsConnector as NativeTlsConnector}, TlsConnector};
+
+/// Trust settings for TLS connections.
+///
+/// By default the server certificate must chain to one of the system root
+/// CAs and match the server name. Extra roots can be added from PEM files,
+/// e.g. for a company CA. Skipping verification altogether requires
+/// `danger_accept_invalid_certs(true)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    m_root_certificate_files: Vec<PathBuf>,
+    m_accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    /// Trusts every certificate in the PEM file in addition to the system roots.
+    pub fn add_root_certificate_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.m_root_certificate_files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Accepts any certificate for any name. The connection is then open to
+    /// man-in-the-middle attacks; only use it against test servers.
+    pub fn danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.m_accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    pub fn accepts_invalid_certs(&self) -> bool {
+        self.m_accept_invalid_certs
+    }
+
+    pub fn build_connector(&self) -> Result<TlsConnector, Error> {
+        let mut builder = NativeTlsConnector::builder();
+
+        for path in &self.m_root_certificate_files {
+            let pem = std::fs::read_to_string(path)?;
+            let certificates = Self::split_pem_certificates(&pem);
+
+            if certificates.is_empty() {
+                return Err(Error::TlsUpgrade(format!("No certificate found in {}", path.display())));
+            }
+
+            for certificate in certificates {
+                builder.add_root_certificate(Certificate::from_pem(certificate.as_bytes())?);
+            }
+        }
+
+        if self.m_accept_invalid_certs {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+
+        Ok(TlsConnector::from(builder.build()?))
+    }
+
+    fn split_pem_certificates(pem: &str) -> Vec<String> {
+        const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+        const END: &str = "-----END CERTIFICATE-----";
+
+        let mut certificates = Vec::new();
+        let mut rest = pem;
+
+        while let Some(start) = rest.find(BEGIN) {
+            let Some(end) = rest[start..].find(END) else { break; };
+            let end = start + end + END.len();
+
+            certificates.push(rest[start..end].to_string());
+            rest = &rest[end..];
+        }
+
+        certificates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::TestPki;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Runs a one-shot local TLS server and connects to it with `config`,
+    /// verifying the certificate against `domain`.
+    async fn handshake(pki: &TestPki, config: &TlsConfig, domain: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let acceptor = pki.acceptor();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(mut tls_stream) = acceptor.accept(stream).await {
+                let _ = tls_stream.write_all(b"220 ready\r\n").await;
+            }
+        });
+
+        let connector = config.build_connector()?;
+        let stream = TcpStream::connect(address).await?;
+        let result = connector.connect(domain, stream).await;
+        let result = match result {
+            Ok(mut tls_stream) => {
+                let mut greeting = [0; 11];
+                tls_stream.read_exact(&mut greeting).await?;
+                assert_eq!(&greeting, b"220 ready\r\n");
+                Ok(())
+            },
+            Err(err) => Err(Error::from(err)),
+        };
+
+        server.await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_ca_is_rejected_by_default() {
+        let pki = TestPki::new();
+        assert!(handshake(&pki, &TlsConfig::default(), "localhost").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_extra_root_certificate_is_trusted() {
+        let pki = TestPki::new();
+        let config = TlsConfig::default().add_root_certificate_file(pki.get_ca_file());
+
+        assert_eq!(handshake(&pki, &config, "localhost").await, Ok(()));
+        assert_eq!(handshake(&pki, &config, "127.0.0.1").await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_hostname_mismatch_is_rejected() {
+        let pki = TestPki::new();
+        let config = TlsConfig::default().add_root_certificate_file(pki.get_ca_file());
+
+        assert!(handshake(&pki, &config, "smtp.example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_insecure_option_accepts_anything() {
+        let pki = TestPki::new();
+        let config = TlsConfig::default().danger_accept_invalid_certs(true);
+
+        assert_eq!(handshake(&pki, &config, "smtp.example.com").await, Ok(()));
+    }
+
+    #[test]
+    fn test_missing_and_empty_root_files() {
+        let config = TlsConfig::default().add_root_certificate_file("/nonexistent/ca.pem");
+        assert!(matches!(config.build_connector(), Err(Error::Io(_))));
+
+        let empty_file = std::env::temp_dir().join(format!("async_stream_test_empty_{}.pem", std::process::id()));
+        std::fs::write(&empty_file, "not a certificate").unwrap();
+        let config = TlsConfig::default().add_root_certificate_file(&empty_file);
+        assert!(matches!(config.build_connector(), Err(Error::TlsUpgrade(_))));
+        std::fs::remove_file(&empty_file).unwrap();
+    }
+
+    #[test]
+    fn test_split_pem_certificates() {
+        let pem = "junk\n-----BEGIN CERTIFICATE-----\nAAA\n-----END CERTIFICATE-----\n-----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----\n";
+        assert_eq!(TlsConfig::split_pem_certificates(pem), vec![
+            "-----BEGIN CERTIFICATE-----\nAAA\n-----END CERTIFICATE-----",
+            "-----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----",
+        ]);
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use tokio::{io::Result, signal::unix::{signal, SignalKind}, sync::Mutex};
-use smtp_session::{SmtpSession, SmtpMessage};
+use smtp_session::{ConnectionSecurity, Credentials, SmtpSession, SmtpMessage, TlsConfig};
 
 use std::io::{stdin, stdout, Write};
 
@@ -62,12 +62,22 @@ async fn main() -> Result<()> {
                     }
                 };
 
-                let shadow_session = SmtpSession::connect(&server);
+                let security = if server.ends_with(":465") {
+                    ConnectionSecurity::Implicit
+                } else {
+                    ConnectionSecurity::Plain
+                };
+                let shadow_session = SmtpSession::connect(&server, security, TlsConfig::default());
 
                 match shadow_session.await {
-                    Ok(shadow_session) => {
+                    Ok(mut shadow_session) => {
+                        shadow_session.set_trace(Some(|text| { print_w_flush!("{}", text); }));
+                        state = if shadow_session.is_encrypted() {
+                            State::Encrypted
+                        } else {
+                            State::Connected
+                        };
                         *session.lock().await = Some(shadow_session);
-                        state = State::Connected;
                     }
                     Err(err) => {
                         *session.lock().await = None;
@@ -141,8 +151,9 @@ async fn main() -> Result<()> {
                 print_w_flush!("{username}, {password}");
 
                 if let Some(session) = session.lock().await.as_mut() {
-                    match session.authenticate(&username, &password).await {
-                        Ok(_) => {
+                    match session.authenticate_auto(&Credentials::password(&username, &password)).await {
+                        Ok(mechanism) => {
+                            print_w_flush!("\nAuthenticated with {}\n", mechanism);
                             state = State::Authenticated;
                         }
                         Err(err) => {
@@ -216,7 +227,11 @@ async fn main() -> Result<()> {
                     Ok(message) => {
                         if let Some(session) = session.lock().await.as_mut() {
                             match session.send_message(message).await {
-                                Ok(_) => {
+                                Ok(report) => {
+                                    print_w_flush!("Message-ID: <{}>\n", report.get_message_id());
+                                    for rejected in report.get_rejected() {
+                                        print_w_flush!("Rejected {}: {} {}\n", rejected.address, rejected.code, rejected.text);
+                                    }
                                     state = State::MessageSent;
                                 }
                                 Err(err) => {
@@ -241,7 +256,6 @@ async fn main() -> Result<()> {
                         response = response.trim().to_string();
                     }
                     Err(err) => {
-                        state = State::MessageSent;
                         print_w_flush!("Error: {}", err);
                     }
                 };
@@ -1,6 +1,14 @@
 use std::{fmt::Display, net::AddrParseError};
 use tokio::time::error::Elapsed;
 
+mod oauth2;
+mod smtp_command;
+mod smtp_reply;
+
+pub use oauth2::OAuth2Error;
+pub use smtp_command::SmtpCommand;
+pub use smtp_reply::{EnhancedStatusCode, SmtpReplyError, SmtpStatus};
+
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
@@ -10,6 +18,9 @@ pub enum Error {
     AsyncStream(String),
     ClosedConnection(String),
     SmtpResponse(String),
+    SmtpReply(SmtpReplyError),
+    Sasl(String),
+    OAuth2(OAuth2Error),
     MessageBuild(String),
     Timeout(String),
 }
@@ -24,6 +35,9 @@ impl PartialEq for Error {
             (Error::AsyncStream(a), Error::AsyncStream(b)) => a == b,
             (Error::ClosedConnection(a), Error::ClosedConnection(b)) => a == b,
             (Error::SmtpResponse(a), Error::SmtpResponse(b)) => a == b,
+            (Error::SmtpReply(a), Error::SmtpReply(b)) => a == b,
+            (Error::Sasl(a), Error::Sasl(b)) => a == b,
+            (Error::OAuth2(a), Error::OAuth2(b)) => a == b,
             (Error::MessageBuild(a), Error::MessageBuild(b)) => a == b,
             (Error::Timeout(a), Error::Timeout(b)) => a == b,
             _ => false,
@@ -33,16 +47,36 @@ impl PartialEq for Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Error::ClosedConnection(ref msg) = self {
-            write!(f, "Connection was closed on try to: {}\n", msg)
-        } else {
-            write!(f, "{:?}\n", self)
+        match self {
+            Error::ClosedConnection(msg) => writeln!(f, "Connection was closed on try to: {}", msg),
+            Error::SmtpReply(reply) => writeln!(f, "{}", reply),
+            Error::OAuth2(oauth2) => writeln!(f, "{}", oauth2),
+            _ => writeln!(f, "{:?}", self),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+impl Error {
+    /// Returns `true` for failures that may succeed when the transaction is
+    /// retried later: 4xx replies, timeouts and dropped connections.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::SmtpReply(reply) => reply.code / 100 == 4,
+            Error::Timeout(_) => true,
+            Error::ClosedConnection(_) => true,
+            Error::Io(err) => matches!(err.kind(),
+                std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::TimedOut),
+            _ => false,
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::Io(err)
@@ -65,4 +99,31 @@ impl From<Elapsed> for Error {
     fn from(err: Elapsed) -> Self {
         Error::Timeout(err.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply_error(code: u16) -> Error {
+        Error::SmtpReply(SmtpReplyError {
+            command: Some(SmtpCommand::RcptTo),
+            expected: SmtpStatus::PositiveCompletion,
+            code,
+            enhanced_code: None,
+            text: String::new(),
+        })
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(reply_error(421).is_retryable());
+        assert!(reply_error(452).is_retryable());
+        assert!(!reply_error(550).is_retryable());
+        assert!(Error::Timeout("Read".to_string()).is_retryable());
+        assert!(Error::ClosedConnection("Read the rest of a reply".to_string()).is_retryable());
+        assert!(Error::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).is_retryable());
+        assert!(!Error::Io(std::io::Error::from(std::io::ErrorKind::PermissionDenied)).is_retryable());
+        assert!(!Error::MessageBuild("Missing 'from' field".to_string()).is_retryable());
+    }
+}
@@ -0,0 +1,23 @@
+use std::fmt;
+
+/// The error a server reports in the JSON challenge of a failed XOAUTH2 or
+/// OAUTHBEARER exchange (RFC 7628 section 3.2.2).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OAuth2Error {
+    pub status: String,
+    pub schemes: Option<String>,
+    pub scope: Option<String>,
+    pub openid_configuration: Option<String>,
+}
+
+impl fmt::Display for OAuth2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OAuth2 authentication failed with status {}", self.status)?;
+
+        if let Some(scope) = &self.scope {
+            write!(f, ", scope: {}", scope)?;
+        }
+
+        Ok(())
+    }
+}
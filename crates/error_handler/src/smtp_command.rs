@@ -0,0 +1,38 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpCommand {
+    Ehlo,
+    StartTls,
+    Register,
+    Auth,
+    AuthPlain,
+    AuthLogin,
+    MailFrom,
+    RcptTo,
+    Data,
+    Bdat,
+    Rset,
+    Quit,
+    Dot,
+}
+
+impl fmt::Display for SmtpCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ehlo => write!(f, "EHLO"),
+            Self::StartTls => write!(f, "STARTTLS"),
+            Self::Register => write!(f, "REGISTER"),
+            Self::Auth => write!(f, "AUTH"),
+            Self::AuthPlain => write!(f, "AUTH PLAIN"),
+            Self::AuthLogin => write!(f, "AUTH LOGIN"),
+            Self::MailFrom => write!(f, "MAIL FROM:"),
+            Self::RcptTo => write!(f, "RCPT TO:"),
+            Self::Data => write!(f, "DATA"),
+            Self::Bdat => write!(f, "BDAT"),
+            Self::Rset => write!(f, "RSET"),
+            Self::Quit => write!(f, "QUIT"),
+            Self::Dot => write!(f, "\r\n.\r\n"),
+        }
+    }
+}
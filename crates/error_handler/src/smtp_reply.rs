@@ -0,0 +1,256 @@
+use std::fmt;
+
+use crate::SmtpCommand;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpStatus {
+    PositiveCompletion,
+    PositiveIntermediate,
+    TransientNegativeCompletion,
+    PermanentNegativeCompletion,
+    Unknown,
+}
+
+impl From<u16> for SmtpStatus {
+    fn from(status: u16) -> Self {
+        match status - (status % 100) {
+            200 => SmtpStatus::PositiveCompletion,
+            300 => SmtpStatus::PositiveIntermediate,
+            400 => SmtpStatus::TransientNegativeCompletion,
+            500 => SmtpStatus::PermanentNegativeCompletion,
+            _ => SmtpStatus::Unknown,
+        }
+    }
+}
+
+/// RFC 3463 enhanced mail system status code, e.g. `5.1.1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EnhancedStatusCode {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedStatusCode {
+    pub fn new(class: u8, subject: u16, detail: u16) -> Self {
+        Self { class, subject, detail }
+    }
+
+    /// Parses the code at the start of a reply text line, as sent by servers
+    /// advertising ENHANCEDSTATUSCODES: `2.1.0 Sender OK`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let token = text.split(char::is_whitespace).next()?;
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let is_number = |part: &str, max_len: usize| {
+            !part.is_empty() && part.len() <= max_len && part.bytes().all(|byte| byte.is_ascii_digit())
+        };
+        if parts.len() != 3 || !is_number(parts[0], 1) || !is_number(parts[1], 3) || !is_number(parts[2], 3) {
+            return None;
+        }
+
+        let class: u8 = parts[0].parse().ok()?;
+        if !matches!(class, 2 | 4 | 5) {
+            return None;
+        }
+
+        Some(Self {
+            class,
+            subject: parts[1].parse().ok()?,
+            detail: parts[2].parse().ok()?,
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.class == 2
+    }
+
+    pub fn is_persistent_transient_failure(&self) -> bool {
+        self.class == 4
+    }
+
+    pub fn is_permanent_failure(&self) -> bool {
+        self.class == 5
+    }
+
+    /// Returns the standard meaning of the subject and detail pair from the
+    /// IANA "Enumerated Status Codes" registry (RFC 3463, RFC 5248).
+    pub fn description(&self) -> Option<&'static str> {
+        let description = match (self.subject, self.detail) {
+            (0, 0) => "Other undefined status",
+            (1, 0) => "Other address status",
+            (1, 1) => "Bad destination mailbox address",
+            (1, 2) => "Bad destination system address",
+            (1, 3) => "Bad destination mailbox address syntax",
+            (1, 4) => "Destination mailbox address ambiguous",
+            (1, 5) => "Destination address valid",
+            (1, 6) => "Destination mailbox has moved, no forwarding address",
+            (1, 7) => "Bad sender's mailbox address syntax",
+            (1, 8) => "Bad sender's system address",
+            (1, 9) => "Message relayed to non-compliant mailer",
+            (1, 10) => "Recipient address has null MX",
+            (2, 0) => "Other or undefined mailbox status",
+            (2, 1) => "Mailbox disabled, not accepting messages",
+            (2, 2) => "Mailbox full",
+            (2, 3) => "Message length exceeds administrative limit",
+            (2, 4) => "Mailing list expansion problem",
+            (3, 0) => "Other or undefined mail system status",
+            (3, 1) => "Mail system full",
+            (3, 2) => "System not accepting network messages",
+            (3, 3) => "System not capable of selected features",
+            (3, 4) => "Message too big for system",
+            (3, 5) => "System incorrectly configured",
+            (3, 6) => "Requested priority was changed",
+            (4, 0) => "Other or undefined network or routing status",
+            (4, 1) => "No answer from host",
+            (4, 2) => "Bad connection",
+            (4, 3) => "Directory server failure",
+            (4, 4) => "Unable to route",
+            (4, 5) => "Mail system congestion",
+            (4, 6) => "Routing loop detected",
+            (4, 7) => "Delivery time expired",
+            (5, 0) => "Other or undefined protocol status",
+            (5, 1) => "Invalid command",
+            (5, 2) => "Syntax error",
+            (5, 3) => "Too many recipients",
+            (5, 4) => "Invalid command arguments",
+            (5, 5) => "Wrong protocol version",
+            (5, 6) => "Authentication exchange line is too long",
+            (6, 0) => "Other or undefined media error",
+            (6, 1) => "Media not supported",
+            (6, 2) => "Conversion required and prohibited",
+            (6, 3) => "Conversion required but not supported",
+            (6, 4) => "Conversion with loss performed",
+            (6, 5) => "Conversion failed",
+            (6, 6) => "Message content not available",
+            (6, 7) => "Non-ASCII addresses not permitted for that sender/recipient",
+            (6, 8) => "UTF-8 string reply is required, but not permitted by the SMTP client",
+            (6, 9) => "UTF-8 header message cannot be transferred to one or more recipients",
+            (7, 0) => "Other or undefined security status",
+            (7, 1) => "Delivery not authorized, message refused",
+            (7, 2) => "Mailing list expansion prohibited",
+            (7, 3) => "Security conversion required but not possible",
+            (7, 4) => "Security features not supported",
+            (7, 5) => "Cryptographic failure",
+            (7, 6) => "Cryptographic algorithm not supported",
+            (7, 7) => "Message integrity failure",
+            (7, 8) => "Authentication credentials invalid",
+            (7, 9) => "Authentication mechanism is too weak",
+            (7, 10) => "Encryption needed",
+            (7, 11) => "Encryption required for requested authentication mechanism",
+            (7, 12) => "A password transition is needed",
+            (7, 13) => "User account disabled",
+            (7, 14) => "Trust relationship required",
+            (7, 15) => "Priority level is too low",
+            (7, 16) => "Message is too big for the specified priority",
+            (7, 17) => "Mailbox owner has changed",
+            (7, 18) => "Domain owner has changed",
+            (7, 19) => "RRVS test cannot be completed",
+            (7, 20) => "No passing DKIM signature found",
+            (7, 21) => "No acceptable DKIM signature found",
+            (7, 22) => "No valid author-matched DKIM signature found",
+            (7, 23) => "SPF validation failed",
+            (7, 24) => "SPF validation error",
+            (7, 25) => "Reverse DNS validation failed",
+            (7, 26) => "Multiple authentication checks failed",
+            (7, 27) => "Sender address has null MX",
+            (7, 28) => "Mail flood detected",
+            (7, 29) => "ARC validation failure",
+            (7, 30) => "REQUIRETLS support required",
+            _ => return None,
+        };
+
+        Some(description)
+    }
+}
+
+impl fmt::Display for EnhancedStatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// A reply whose status did not match what the command expected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmtpReplyError {
+    /// The command the reply answers, or `None` for the connection greeting.
+    pub command: Option<SmtpCommand>,
+    pub expected: SmtpStatus,
+    pub code: u16,
+    pub enhanced_code: Option<EnhancedStatusCode>,
+    /// The reply text, without the enhanced code at the start of each line.
+    pub text: String,
+}
+
+impl fmt::Display for SmtpReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.command {
+            Some(command) => write!(f, "{} failed: ", command.to_string().trim())?,
+            None => write!(f, "Greeting failed: ")?,
+        }
+
+        write!(f, "expected {:?}, got {}", self.expected, self.code)?;
+
+        if let Some(enhanced_code) = self.enhanced_code {
+            write!(f, " {}", enhanced_code)?;
+            if let Some(description) = enhanced_code.description() {
+                write!(f, " ({})", description)?;
+            }
+        }
+
+        write!(f, ": {}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(EnhancedStatusCode::parse("5.1.1 <nobody@example.com>: Recipient address rejected"),
+            Some(EnhancedStatusCode::new(5, 1, 1)));
+        assert_eq!(EnhancedStatusCode::parse("4.7.28 Mail flood detected"), Some(EnhancedStatusCode::new(4, 7, 28)));
+        assert_eq!(EnhancedStatusCode::parse("2.0.0"), Some(EnhancedStatusCode::new(2, 0, 0)));
+    }
+
+    #[test]
+    fn test_parse_rejects_non_codes() {
+        assert_eq!(EnhancedStatusCode::parse("OK"), None);
+        assert_eq!(EnhancedStatusCode::parse("3.0.0 invalid class"), None);
+        assert_eq!(EnhancedStatusCode::parse("5.1.1000 too long"), None);
+        assert_eq!(EnhancedStatusCode::parse("smtp.example.com 2.0.0"), None);
+    }
+
+    #[test]
+    fn test_description() {
+        assert_eq!(EnhancedStatusCode::new(4, 2, 2).description(), Some("Mailbox full"));
+        assert_eq!(EnhancedStatusCode::new(5, 1, 1).description(), Some("Bad destination mailbox address"));
+        assert_eq!(EnhancedStatusCode::new(5, 9, 9).description(), None);
+    }
+
+    #[test]
+    fn test_status_from() {
+        assert_eq!(SmtpStatus::from(354), SmtpStatus::PositiveIntermediate);
+        assert_eq!(SmtpStatus::from(421), SmtpStatus::TransientNegativeCompletion);
+        assert_eq!(SmtpStatus::from(120), SmtpStatus::Unknown);
+    }
+
+    #[test]
+    fn test_reply_error_display() {
+        let error = SmtpReplyError {
+            command: Some(SmtpCommand::RcptTo),
+            expected: SmtpStatus::PositiveCompletion,
+            code: 550,
+            enhanced_code: Some(EnhancedStatusCode::new(5, 1, 1)),
+            text: "User unknown".to_string(),
+        };
+        assert_eq!(error.to_string(),
+            "RCPT TO: failed: expected PositiveCompletion, got 550 5.1.1 (Bad destination mailbox address): User unknown");
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(EnhancedStatusCode::new(5, 7, 8).to_string(), "5.7.8");
+    }
+}
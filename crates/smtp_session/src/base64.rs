@@ -1,5 +1,15 @@
 use base64::{engine::general_purpose::STANDARD, Engine as _};
+use error_handler::Error;
 
 pub fn encode(data: &str) -> String {
-    STANDARD.encode(data.as_bytes())
-}
\ No newline at end of file
+    encode_bytes(data.as_bytes())
+}
+
+pub fn encode_bytes(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>, Error> {
+    STANDARD.decode(data.trim())
+        .map_err(|_| Error::SmtpResponse(format!("Invalid base64 data: {}", data)))
+}
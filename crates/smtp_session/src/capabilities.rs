@@ -0,0 +1,243 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    CramMd5,
+    ScramSha1,
+    ScramSha256,
+    XOAuth2,
+    OAuthBearer,
+    Other(String),
+}
+
+impl From<&str> for AuthMechanism {
+    fn from(mechanism: &str) -> Self {
+        match mechanism.to_ascii_uppercase().as_str() {
+            "PLAIN" => AuthMechanism::Plain,
+            "LOGIN" => AuthMechanism::Login,
+            "CRAM-MD5" => AuthMechanism::CramMd5,
+            "SCRAM-SHA-1" => AuthMechanism::ScramSha1,
+            "SCRAM-SHA-256" => AuthMechanism::ScramSha256,
+            "XOAUTH2" => AuthMechanism::XOAuth2,
+            "OAUTHBEARER" => AuthMechanism::OAuthBearer,
+            other => AuthMechanism::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for AuthMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain => write!(f, "PLAIN"),
+            Self::Login => write!(f, "LOGIN"),
+            Self::CramMd5 => write!(f, "CRAM-MD5"),
+            Self::ScramSha1 => write!(f, "SCRAM-SHA-1"),
+            Self::ScramSha256 => write!(f, "SCRAM-SHA-256"),
+            Self::XOAuth2 => write!(f, "XOAUTH2"),
+            Self::OAuthBearer => write!(f, "OAUTHBEARER"),
+            Self::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl AuthMechanism {
+    /// Returns `true` for mechanisms that send a reusable secret (a password
+    /// or a bearer token) in the clear, readable by anyone on the path unless
+    /// the connection is encrypted.
+    pub fn is_plaintext(&self) -> bool {
+        matches!(self, Self::Plain | Self::Login | Self::XOAuth2 | Self::OAuthBearer)
+    }
+}
+
+/// Extensions advertised by the server in its EHLO reply.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerCapabilities {
+    m_size: Option<u64>,
+    m_8bitmime: bool,
+    m_smtputf8: bool,
+    m_pipelining: bool,
+    m_chunking: bool,
+    m_binarymime: bool,
+    m_starttls: bool,
+    m_auth: Vec<AuthMechanism>,
+    m_dsn: bool,
+    m_enhanced_status_codes: bool,
+    m_unknown: Vec<String>,
+}
+
+impl ServerCapabilities {
+    /// Builds the capability set from the text lines of a 250 EHLO reply.
+    /// The first line is the server greeting and carries no extension.
+    pub fn from_ehlo_lines<S: AsRef<str>>(lines: &[S]) -> Self {
+        let mut capabilities = Self::default();
+
+        for line in lines.iter().skip(1) {
+            capabilities.parse_keyword_line(line.as_ref());
+        }
+
+        capabilities
+    }
+
+    fn parse_keyword_line(&mut self, line: &str) {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else { return; };
+
+        // Some older servers still send the pre-standard "AUTH=LOGIN PLAIN" form.
+        let (keyword, first_param) = match keyword.split_once('=') {
+            Some((keyword, param)) => (keyword, Some(param)),
+            None => (keyword, None),
+        };
+        let params: Vec<&str> = first_param.into_iter()
+            .filter(|param| !param.is_empty())
+            .chain(tokens)
+            .collect();
+
+        match keyword.to_ascii_uppercase().as_str() {
+            "SIZE" => {
+                self.m_size = Some(params.first().and_then(|size| size.parse().ok()).unwrap_or(0));
+            },
+            "8BITMIME" => self.m_8bitmime = true,
+            "SMTPUTF8" => self.m_smtputf8 = true,
+            "PIPELINING" => self.m_pipelining = true,
+            "CHUNKING" => self.m_chunking = true,
+            "BINARYMIME" => self.m_binarymime = true,
+            "STARTTLS" => self.m_starttls = true,
+            "AUTH" => {
+                for mechanism in params.into_iter().map(AuthMechanism::from) {
+                    if !self.m_auth.contains(&mechanism) {
+                        self.m_auth.push(mechanism);
+                    }
+                }
+            },
+            "DSN" => self.m_dsn = true,
+            "ENHANCEDSTATUSCODES" => self.m_enhanced_status_codes = true,
+            _ => self.m_unknown.push(line.trim().to_string()),
+        }
+    }
+
+    pub fn supports_size(&self) -> bool {
+        self.m_size.is_some()
+    }
+
+    /// Returns the declared maximum message size, or `None` when the server
+    /// does not advertise SIZE or advertises it without a fixed limit.
+    pub fn get_max_message_size(&self) -> Option<u64> {
+        self.m_size.filter(|size| *size > 0)
+    }
+
+    pub fn supports_8bitmime(&self) -> bool {
+        self.m_8bitmime
+    }
+
+    pub fn supports_smtputf8(&self) -> bool {
+        self.m_smtputf8
+    }
+
+    pub fn supports_pipelining(&self) -> bool {
+        self.m_pipelining
+    }
+
+    pub fn supports_chunking(&self) -> bool {
+        self.m_chunking
+    }
+
+    /// BINARYMIME (RFC 3030) is only usable together with CHUNKING.
+    pub fn supports_binarymime(&self) -> bool {
+        self.m_binarymime
+    }
+
+    pub fn supports_starttls(&self) -> bool {
+        self.m_starttls
+    }
+
+    pub fn supports_auth(&self, mechanism: &AuthMechanism) -> bool {
+        self.m_auth.contains(mechanism)
+    }
+
+    pub fn get_auth_mechanisms(&self) -> &[AuthMechanism] {
+        &self.m_auth
+    }
+
+    pub fn supports_dsn(&self) -> bool {
+        self.m_dsn
+    }
+
+    pub fn supports_enhanced_status_codes(&self) -> bool {
+        self.m_enhanced_status_codes
+    }
+
+    pub fn get_unknown(&self) -> &[String] {
+        &self.m_unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_ehlo_lines() {
+        let lines = [
+            "smtp.gmail.com at your service, [217.65.241.77]",
+            "SIZE 35882577",
+            "8BITMIME",
+            "STARTTLS",
+            "ENHANCEDSTATUSCODES",
+            "PIPELINING",
+            "CHUNKING",
+            "BINARYMIME",
+            "SMTPUTF8",
+        ];
+        let capabilities = ServerCapabilities::from_ehlo_lines(&lines);
+
+        assert_eq!(capabilities.get_max_message_size(), Some(35882577));
+        assert!(capabilities.supports_8bitmime());
+        assert!(capabilities.supports_starttls());
+        assert!(capabilities.supports_enhanced_status_codes());
+        assert!(capabilities.supports_pipelining());
+        assert!(capabilities.supports_chunking());
+        assert!(capabilities.supports_binarymime());
+        assert!(capabilities.supports_smtputf8());
+        assert!(!capabilities.supports_dsn());
+        assert!(capabilities.get_auth_mechanisms().is_empty());
+        assert!(capabilities.get_unknown().is_empty());
+    }
+
+    #[test]
+    fn test_auth_mechanisms() {
+        let lines = [
+            "mail.example.com",
+            "AUTH PLAIN LOGIN XOAUTH2 x-custom",
+            "AUTH=LOGIN PLAIN",
+        ];
+        let capabilities = ServerCapabilities::from_ehlo_lines(&lines);
+
+        assert_eq!(capabilities.get_auth_mechanisms(), &[
+            AuthMechanism::Plain,
+            AuthMechanism::Login,
+            AuthMechanism::XOAuth2,
+            AuthMechanism::Other("X-CUSTOM".to_string()),
+        ]);
+        assert!(capabilities.supports_auth(&AuthMechanism::Login));
+        assert!(!capabilities.supports_auth(&AuthMechanism::CramMd5));
+    }
+
+    #[test]
+    fn test_size_without_limit_and_unknown_keywords() {
+        let lines = ["mail.example.com", "SIZE", "DSN", "VRFY", "X-EXPS GSSAPI NTLM"];
+        let capabilities = ServerCapabilities::from_ehlo_lines(&lines);
+
+        assert!(capabilities.supports_size());
+        assert_eq!(capabilities.get_max_message_size(), None);
+        assert!(capabilities.supports_dsn());
+        assert_eq!(capabilities.get_unknown(), &["VRFY".to_string(), "X-EXPS GSSAPI NTLM".to_string()]);
+    }
+
+    #[test]
+    fn test_greeting_only() {
+        let capabilities = ServerCapabilities::from_ehlo_lines(&["mail.example.com"]);
+        assert_eq!(capabilities, ServerCapabilities::default());
+    }
+}
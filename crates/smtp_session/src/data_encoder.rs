@@ -0,0 +1,221 @@
+/// Prepares message content for the wire, one piece at a time.
+///
+/// Line endings are normalized to CRLF: bare LF and bare CR each become a
+/// line break. For DATA, lines starting with "." get a second one
+/// (transparency, RFC 5321 section 4.5.2) and `finish` appends the
+/// terminating "." line.
+pub struct DataEncoder {
+    m_dot_stuffing: bool,
+    m_at_line_start: bool,
+    m_pending_cr: bool,
+}
+
+impl Default for DataEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DataEncoder {
+    /// An encoder for content sent after DATA.
+    pub fn new() -> Self {
+        Self {
+            m_dot_stuffing: true,
+            m_at_line_start: true,
+            m_pending_cr: false,
+        }
+    }
+
+    /// An encoder that only normalizes line endings, for content sent with
+    /// BDAT, which is not dot-terminated.
+    pub fn line_endings_only() -> Self {
+        Self {
+            m_dot_stuffing: false,
+            ..Self::new()
+        }
+    }
+
+    /// Encodes the next piece of content. A CRLF split between two pieces
+    /// is handled.
+    pub fn encode(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(input.len() + input.len() / 64 + 2);
+
+        for &byte in input {
+            if self.m_pending_cr {
+                self.m_pending_cr = false;
+                self.end_line(&mut output);
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+
+            match byte {
+                b'\r' => self.m_pending_cr = true,
+                b'\n' => self.end_line(&mut output),
+                _ => {
+                    if self.m_at_line_start && self.m_dot_stuffing && byte == b'.' {
+                        output.push(b'.');
+                    }
+                    output.push(byte);
+                    self.m_at_line_start = false;
+                },
+            }
+        }
+
+        output
+    }
+
+    /// Ends the content: closes an unterminated last line and, for DATA,
+    /// appends the "." line.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        if self.m_pending_cr || !self.m_at_line_start {
+            self.m_pending_cr = false;
+            self.end_line(&mut output);
+        }
+
+        if self.m_dot_stuffing {
+            output.extend_from_slice(b".\r\n");
+        }
+
+        output
+    }
+
+    fn end_line(&mut self, output: &mut Vec<u8>) {
+        output.extend_from_slice(b"\r\n");
+        self.m_at_line_start = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Recovers the content from dot-stuffed DATA, up to the "." line.
+    fn decode_data(data: &[u8]) -> Option<Vec<u8>> {
+        let mut content = Vec::with_capacity(data.len());
+        let mut rest = data;
+
+        while !rest.is_empty() {
+            let end = rest.windows(2).position(|pair| pair == b"\r\n")?;
+            let (line, next) = (&rest[..end], &rest[end + 2..]);
+
+            if line == b"." {
+                return Some(content);
+            }
+
+            content.extend_from_slice(line.strip_prefix(b".").unwrap_or(line));
+            content.extend_from_slice(b"\r\n");
+            rest = next;
+        }
+
+        None
+    }
+
+    fn encode_all(input: &[u8]) -> Vec<u8> {
+        let mut encoder = DataEncoder::new();
+        let mut output = encoder.encode(input);
+        output.extend(encoder.finish());
+        output
+    }
+
+    /// The content as it should arrive: CRLF line endings, last line closed.
+    fn normalize(input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut i = 0;
+        while i < input.len() {
+            match input[i] {
+                b'\r' if input.get(i + 1) == Some(&b'\n') => { output.extend_from_slice(b"\r\n"); i += 1; },
+                b'\r' | b'\n' => output.extend_from_slice(b"\r\n"),
+                byte => output.push(byte),
+            }
+            i += 1;
+        }
+        if !output.is_empty() && !output.ends_with(b"\r\n") {
+            output.extend_from_slice(b"\r\n");
+        }
+        output
+    }
+
+    #[test]
+    fn test_dot_stuffing() {
+        assert_eq!(encode_all(b"Hi\r\n.\r\n..and more\r\n.end"), b"Hi\r\n..\r\n...and more\r\n..end\r\n.\r\n");
+        assert_eq!(encode_all(b".leading"), b"..leading\r\n.\r\n");
+        assert_eq!(encode_all(b"a.b\r\n"), b"a.b\r\n.\r\n");
+        assert_eq!(encode_all(b""), b".\r\n");
+    }
+
+    #[test]
+    fn test_line_ending_normalization() {
+        assert_eq!(encode_all(b"one\ntwo\rthree\r\nfour"), b"one\r\ntwo\r\nthree\r\nfour\r\n.\r\n");
+        assert_eq!(encode_all(b"\n.\n"), b"\r\n..\r\n.\r\n");
+        assert_eq!(encode_all(b"end\r"), b"end\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_split_across_pieces() {
+        let mut encoder = DataEncoder::new();
+        let mut output = encoder.encode(b"line\r");
+        output.extend(encoder.encode(b"\n."));
+        output.extend(encoder.encode(b"\r\n"));
+        output.extend(encoder.finish());
+        assert_eq!(output, b"line\r\n..\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_line_endings_only() {
+        let mut encoder = DataEncoder::line_endings_only();
+        let mut output = encoder.encode(b".a\n.");
+        output.extend(encoder.finish());
+        assert_eq!(output, b".a\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode_data(b"no terminator\r\n").is_none());
+        assert!(decode_data(b"bare\n.\r\n").is_none());
+    }
+
+    /// Arbitrary bytes, heavy on the ones the encoder treats specially.
+    fn content() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(prop_oneof![Just(b'.'), Just(b'\r'), Just(b'\n'), any::<u8>()], 0..256)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(input in content()) {
+            prop_assert_eq!(decode_data(&encode_all(&input)).unwrap(), normalize(&input));
+        }
+
+        #[test]
+        fn prop_round_trip_in_pieces(input in content(), piece_size in 1usize..8) {
+            let mut encoder = DataEncoder::new();
+            let mut encoded: Vec<u8> = input.chunks(piece_size).flat_map(|piece| encoder.encode(piece)).collect();
+            encoded.extend(encoder.finish());
+
+            prop_assert_eq!(&encoded, &encode_all(&input));
+        }
+
+        #[test]
+        fn prop_only_crlf_and_single_terminator(input in content()) {
+            let encoded = encode_all(&input);
+
+            for (i, byte) in encoded.iter().enumerate() {
+                match byte {
+                    b'\r' => prop_assert_eq!(encoded.get(i + 1), Some(&b'\n')),
+                    b'\n' => prop_assert!(i > 0 && encoded[i - 1] == b'\r'),
+                    _ => {},
+                }
+            }
+
+            // Splitting leaves an empty piece after the final line break.
+            let lines: Vec<&[u8]> = encoded.split(|byte| *byte == b'\n')
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .collect();
+            prop_assert_eq!(lines.iter().filter(|line| **line == b".").count(), 1);
+            prop_assert_eq!(lines[lines.len() - 2], b".");
+        }
+    }
+}
@@ -1,66 +1,206 @@
-use std::fmt;
-
 use async_stream::AsyncStream;
+pub use async_stream::TlsConfig;
+pub use error_handler::SmtpCommand;
 use error_handler::Error;
 
 mod base64;
+mod capabilities;
+mod data_encoder;
 mod message;
+mod mime;
+mod report;
+mod retry;
+mod sasl;
 mod smtp_response;
+mod timeouts;
+
+pub use capabilities::{AuthMechanism, ServerCapabilities};
+pub use data_encoder::DataEncoder;
+pub use message::{system_clock, Attachment, AttachmentSource, Clock, InlineImage, SmtpMessage, SmtpMessageBuilder};
+pub use mime::{decode_header_value, parse_headers};
+pub use report::{RecipientPolicy, RecipientStatus, SendReport};
+pub use retry::RetryPolicy;
+pub use sasl::{CramMd5, Credentials, OAuthBearer, SaslMechanism, Scram, XOAuth2};
+pub use smtp_response::{EnhancedStatusCode, SmtpResponse, SmtpStatus};
+pub use timeouts::{SmtpPhase, Timeouts};
+
+use smtp_response::SmtpResponseBuilder;
+use tokio::time::timeout;
 
-pub use message::{SmtpMessage, SmtpMessageBuilder};
+/// Largest BDAT chunk sent by default.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
 
-use smtp_response::{SmtpResponse, SmtpResponseBuilder, SmtpStatus};
-use tokio::time::{timeout, Duration};
+/// Amount of message content encoded and written at a time after DATA.
+const DATA_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Most commands written at once when pipelining. The replies to a batch are
+/// read before the next one goes out, so client and server cannot both block
+/// writing into full buffers (RFC 2920 section 3.1).
+const PIPELINING_BATCH_SIZE: usize = 64;
 
 use SmtpCommand::*;
-pub enum SmtpCommand {
-    Ehlo,
-    StartTls,
-    Register,
-    AuthPlain,
-    MailFrom,
-    RcptTo,
-    Data,
-    Quit,
-    Dot,
-}
 
-impl fmt::Display for SmtpCommand {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Ehlo => write!(f, "EHLO"),
-            Self::StartTls => write!(f, "STARTTLS"),
-            Self::Register => write!(f, "REGISTER"),
-            Self::AuthPlain => write!(f, "AUTH PLAIN"),
-            Self::MailFrom => write!(f, "MAIL FROM:"),
-            Self::RcptTo => write!(f, "RCPT TO:"),
-            Self::Data => write!(f, "DATA"),
-            Self::Quit => write!(f, "QUIT"),
-            Self::Dot => write!(f, "\r\n.\r\n"),
-        }
-    }
+/// How the connection to the server is protected.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConnectionSecurity {
+    /// No TLS unless `encrypt_connection` is called.
+    Plain,
+    /// Upgrade with STARTTLS when the server offers it, stay plain otherwise.
+    StartTls,
+    /// Upgrade with STARTTLS and fail when the server does not offer it.
+    #[default]
+    StartTlsRequired,
+    /// TLS handshake before the greeting, as on port 465 (RFC 8314).
+    Implicit,
 }
 
 pub struct SmtpSession {
     m_stream: AsyncStream,
+    m_capabilities: ServerCapabilities,
+    m_server: String,
+    m_security: ConnectionSecurity,
+    m_tls_config: TlsConfig,
+    m_credentials: Option<Credentials>,
+    m_auth_mechanism: Option<AuthMechanism>,
+    m_allow_plaintext_auth: bool,
+    m_retry_policy: RetryPolicy,
+    m_recipient_policy: RecipientPolicy,
+    m_chunk_size: usize,
+    m_timeouts: Timeouts,
+    m_phase: SmtpPhase,
+    m_trace: Option<fn(&str)>,
 }
 
 impl SmtpSession {
-    pub async fn connect(server: &str) -> Result<Self, Error> {
-        let stream = timeout(Duration::from_secs(5), AsyncStream::new(server))
-            .await?.map_err(Error::from)?;
+    pub async fn connect(server: &str, security: ConnectionSecurity, tls_config: TlsConfig) -> Result<Self, Error> {
+        Self::connect_with_timeouts(server, security, tls_config, Timeouts::default()).await
+    }
+
+    pub async fn connect_with_timeouts(server: &str, security: ConnectionSecurity, tls_config: TlsConfig, timeouts: Timeouts) -> Result<Self, Error> {
+        let connect_timeout = timeouts.get(SmtpPhase::Connect);
+        let stream = match security {
+            ConnectionSecurity::Implicit => timeout(connect_timeout, AsyncStream::new_encrypted(server, &tls_config)).await,
+            _ => timeout(connect_timeout, AsyncStream::new(server)).await,
+        };
+        let stream = stream.map_err(|_| timeouts.error(SmtpPhase::Connect))??;
 
-        let mut smtp_session = Self { m_stream: stream };
+        let mut smtp_session = Self {
+            m_stream: stream,
+            m_capabilities: ServerCapabilities::default(),
+            m_server: server.to_string(),
+            m_security: security,
+            m_tls_config: tls_config,
+            m_credentials: None,
+            m_auth_mechanism: None,
+            m_allow_plaintext_auth: false,
+            m_retry_policy: RetryPolicy::default(),
+            m_recipient_policy: RecipientPolicy::default(),
+            m_chunk_size: DEFAULT_CHUNK_SIZE,
+            m_timeouts: timeouts,
+            m_phase: SmtpPhase::Greeting,
+            m_trace: None,
+        };
 
-        smtp_session.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+        smtp_session.handle_response().await?.status_should_be(None, SmtpStatus::PositiveCompletion)?;
         smtp_session.send_ehlo_cmd().await?;
 
+        match security {
+            ConnectionSecurity::StartTlsRequired => {
+                smtp_session.encrypt_connection().await?;
+            },
+            ConnectionSecurity::StartTls if smtp_session.m_capabilities.supports_starttls() => {
+                smtp_session.encrypt_connection().await?;
+            },
+            _ => {},
+        }
+
         Ok(smtp_session)
     }
 
+    pub fn get_connection_security(&self) -> ConnectionSecurity {
+        self.m_security
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.m_stream.is_encrypted().unwrap_or(false)
+    }
+
+    pub fn get_capabilities(&self) -> &ServerCapabilities {
+        &self.m_capabilities
+    }
+
+    /// Lets `authenticate_auto` use mechanisms that expose the secret on an
+    /// unencrypted connection.
+    pub fn set_allow_plaintext_auth(&mut self, allow: bool) {
+        self.m_allow_plaintext_auth = allow;
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.m_retry_policy = retry_policy;
+    }
+
+    pub fn get_retry_policy(&self) -> &RetryPolicy {
+        &self.m_retry_policy
+    }
+
+    pub fn set_recipient_policy(&mut self, recipient_policy: RecipientPolicy) {
+        self.m_recipient_policy = recipient_policy;
+    }
+
+    pub fn get_recipient_policy(&self) -> RecipientPolicy {
+        self.m_recipient_policy
+    }
+
+    /// Sets the largest chunk of message content sent with one BDAT
+    /// command when the server supports CHUNKING.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.m_chunk_size = chunk_size.max(1);
+    }
+
+    pub fn get_chunk_size(&self) -> usize {
+        self.m_chunk_size
+    }
+
+    /// Replaces the timeouts for the rest of the session. Connect and
+    /// greeting timeouts only take effect on a reconnect; pass them to
+    /// `connect_with_timeouts` for the first connection.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.m_timeouts = timeouts;
+    }
+
+    pub fn get_timeouts(&self) -> &Timeouts {
+        &self.m_timeouts
+    }
+
+    /// Passes every command sent and every reply received, with its line
+    /// endings, to `trace`; `None` turns tracing off, which is the default.
+    /// AUTH payloads are replaced with "<redacted>" and message content is
+    /// left out, so a trace can be logged without exposing secrets.
+    pub fn set_trace(&mut self, trace: Option<fn(&str)>) {
+        self.m_trace = trace;
+    }
+
     pub async fn encrypt_connection(&mut self) -> Result<bool, Error> {
+        if self.is_encrypted() {
+            return Err(Error::TlsUpgrade("Connection is already encrypted".to_string()));
+        }
+
+        if !self.m_capabilities.supports_starttls() {
+            return Err(Error::TlsUpgrade("STARTTLS is not supported by the server".to_string()));
+        }
+
         self.send_starttls_cmd().await?;
-        self.m_stream.try_upgrade_to_tls().await?;
+        match timeout(self.m_timeouts.get(self.m_phase), self.m_stream.try_upgrade_to_tls(&self.m_tls_config)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                self.m_stream.close();
+                return Err(self.m_timeouts.error(self.m_phase));
+            },
+        }
+
+        // RFC 3207: the capabilities learned before the handshake must be discarded.
+        self.m_capabilities = ServerCapabilities::default();
+        self.send_ehlo_cmd().await?;
         Ok(true)
     }
 
@@ -70,111 +210,1489 @@ impl SmtpSession {
     }
 
     pub async fn authenticate(&mut self, username: &str, password: &str) -> Result<usize, Error> {
-        let encoded_auth = base64::encode(format!("\0{}\0{}", username, password).as_str());
-        self.send_auth_plain_cmd(encoded_auth.as_str()).await
+        let mechanism = if self.m_capabilities.supports_auth(&AuthMechanism::Plain) {
+            AuthMechanism::Plain
+        } else if self.m_capabilities.supports_auth(&AuthMechanism::Login) {
+            AuthMechanism::Login
+        } else {
+            return Err(Error::Sasl("Neither AUTH PLAIN nor AUTH LOGIN is supported by the server".to_string()));
+        };
+
+        self.authenticate_mechanism(&Credentials::password(username, password), &mechanism).await
+    }
+
+    /// Authenticates with the strongest mechanism both the server and the
+    /// credentials support, and returns the mechanism that was used.
+    ///
+    /// On an unencrypted connection mechanisms that send the secret in the
+    /// clear are skipped unless `set_allow_plaintext_auth(true)` was called.
+    pub async fn authenticate_auto(&mut self, credentials: &Credentials) -> Result<AuthMechanism, Error> {
+        let is_encrypted = self.is_encrypted();
+        let supported: Vec<&AuthMechanism> = credentials.get_mechanisms()
+            .iter()
+            .filter(|mechanism| self.m_capabilities.supports_auth(mechanism))
+            .collect();
+
+        let Some(mechanism) = supported.iter()
+            .find(|mechanism| is_encrypted || self.m_allow_plaintext_auth || !mechanism.is_plaintext())
+            .map(|mechanism| (*mechanism).clone())
+        else {
+            return Err(if supported.is_empty() {
+                Error::Sasl("No authentication mechanism supported by both the client and the server".to_string())
+            } else {
+                Error::Sasl("Refusing plaintext authentication on an unencrypted connection".to_string())
+            });
+        };
+
+        self.authenticate_mechanism(credentials, &mechanism).await?;
+        Ok(mechanism)
+    }
+
+    /// Runs the exchange of `mechanism` with `credentials`, and remembers
+    /// both so a reconnect can authenticate the same way.
+    async fn authenticate_mechanism(&mut self, credentials: &Credentials, mechanism: &AuthMechanism) -> Result<usize, Error> {
+        let request = match (credentials, mechanism) {
+            (Credentials::Password { username, password }, AuthMechanism::ScramSha256) => {
+                self.authenticate_with(&mut Scram::sha256(username, password)).await?
+            },
+            (Credentials::Password { username, password }, AuthMechanism::ScramSha1) => {
+                self.authenticate_with(&mut Scram::sha1(username, password)).await?
+            },
+            (Credentials::Password { username, password }, AuthMechanism::CramMd5) => {
+                self.authenticate_with(&mut CramMd5::new(username, password)).await?
+            },
+            (Credentials::Password { username, password }, AuthMechanism::Plain) => {
+                let encoded_auth = base64::encode(format!("\0{}\0{}", username, password).as_str());
+                self.send_auth_plain_cmd(encoded_auth.as_str()).await?
+            },
+            (Credentials::Password { username, password }, AuthMechanism::Login) => {
+                self.send_auth_login_cmd(username, password).await?
+            },
+            (Credentials::OAuth2 { username, access_token }, AuthMechanism::OAuthBearer) => {
+                let mut mechanism = self.oauthbearer(username, access_token);
+                self.authenticate_with(&mut mechanism).await?
+            },
+            (Credentials::OAuth2 { username, access_token }, AuthMechanism::XOAuth2) => {
+                self.authenticate_with(&mut XOAuth2::new(username, access_token)).await?
+            },
+            (_, mechanism) => return Err(Error::Sasl(format!("AUTH {} cannot be used with these credentials", mechanism))),
+        };
+
+        self.m_credentials = Some(credentials.clone());
+        self.m_auth_mechanism = Some(mechanism.clone());
+        Ok(request)
+    }
+
+    /// Authenticates with any SASL mechanism the server advertises, running
+    /// its challenge-response exchange over 334 replies (RFC 4954).
+    pub async fn authenticate_with(&mut self, mechanism: &mut dyn SaslMechanism) -> Result<usize, Error> {
+        let name = mechanism.get_mechanism();
+        if !self.m_capabilities.supports_auth(&name) {
+            return Err(Error::Sasl(format!("AUTH {} is not supported by the server", name)));
+        }
+
+        let mut request = match mechanism.initial_response()? {
+            // A zero-length initial response is sent as a single "=".
+            Some(initial) if initial.is_empty() => self.send_cmd_with_arg(Auth, &format!("{} =", name)).await?,
+            Some(initial) => self.send_cmd_with_arg(Auth, &format!("{} {}", name, base64::encode_bytes(&initial))).await?,
+            None => self.send_cmd_with_arg(Auth, &name.to_string()).await?,
+        };
+
+        loop {
+            let response = self.handle_response().await?;
+
+            if response.get_status() != SmtpStatus::PositiveIntermediate {
+                if let Err(err) = response.status_should_be(Some(&Auth), SmtpStatus::PositiveCompletion) {
+                    return Err(mechanism.failure().unwrap_or(err));
+                }
+                mechanism.verify_completion()?;
+                return Ok(request);
+            }
+
+            let answer = base64::decode(&response.get_text()).and_then(|challenge| mechanism.respond(&challenge));
+            match answer {
+                Ok(answer) => request += self.send_line(&base64::encode_bytes(&answer)).await?,
+                Err(err) => {
+                    // Cancel the exchange so the session stays usable.
+                    self.send_line("*").await?;
+                    self.handle_response().await?;
+                    return Err(err);
+                },
+            }
+        }
+    }
+
+    /// Authenticates with an OAuth 2.0 access token, preferring the standard
+    /// OAUTHBEARER over XOAUTH2 when the server offers both. A rejected token
+    /// is reported as `Error::OAuth2` with the server's error details.
+    pub async fn authenticate_oauth2(&mut self, username: &str, access_token: &str) -> Result<usize, Error> {
+        let mechanism = if self.m_capabilities.supports_auth(&AuthMechanism::OAuthBearer) {
+            AuthMechanism::OAuthBearer
+        } else if self.m_capabilities.supports_auth(&AuthMechanism::XOAuth2) {
+            AuthMechanism::XOAuth2
+        } else {
+            return Err(Error::Sasl("Neither AUTH OAUTHBEARER nor AUTH XOAUTH2 is supported by the server".to_string()));
+        };
+
+        self.authenticate_mechanism(&Credentials::oauth2(username, access_token), &mechanism).await
     }
 
-    pub async fn send_message(&mut self, message: SmtpMessage) -> Result<usize, Error> {
+    fn oauthbearer(&self, username: &str, access_token: &str) -> OAuthBearer {
+        let mechanism = OAuthBearer::new(username, access_token);
+
+        match self.m_server.rsplit_once(':').map(|(host, port)| (host, port.parse())) {
+            Some((host, Ok(port))) => mechanism.server(host, port),
+            _ => mechanism,
+        }
+    }
+
+    /// Sends the message, reconnecting and resending according to the retry
+    /// policy when the attempt fails with a retryable error.
+    ///
+    /// Recipients rejected by the server are listed in the returned report;
+    /// whether the message still goes out to the others depends on the
+    /// recipient policy.
+    pub async fn send_message(&mut self, message: SmtpMessage) -> Result<SendReport, Error> {
+        let mut attempt = 1;
+
+        loop {
+            let result = if attempt == 1 {
+                self.send_message_once(&message).await
+            } else {
+                match self.reconnect().await {
+                    Ok(()) => self.send_message_once(&message).await,
+                    Err(err) => Err(err),
+                }
+            };
+
+            match result {
+                Err(err) if err.is_retryable() && attempt < self.m_retry_policy.get_max_attempts() && self.may_resend(&err) => {
+                    tokio::time::sleep(self.m_retry_policy.get_backoff(attempt)).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether resending after `err` cannot deliver the message twice. Once
+    /// the end of the data was sent, only a reply proves the server did not
+    /// accept the message; after a timeout or a dropped connection the
+    /// outcome is unknown.
+    fn may_resend(&self, err: &Error) -> bool {
+        let outcome_unknown = self.m_phase == SmtpPhase::DataTermination && !matches!(err, Error::SmtpReply(_));
+        !outcome_unknown || self.m_retry_policy.get_retry_after_data_termination()
+    }
+
+    async fn send_message_once(&mut self, message: &SmtpMessage) -> Result<SendReport, Error> {
+        let smtputf8 = message.requires_smtputf8();
+        if smtputf8 && !self.m_capabilities.supports_smtputf8() {
+            return Err(Error::MessageBuild("Non-ASCII addresses require SMTPUTF8, which the server does not support".to_string()));
+        }
+
+        let use_bdat = self.m_capabilities.supports_chunking();
+        let binary_imf = match use_bdat && self.m_capabilities.supports_binarymime() {
+            true => message.to_imf_binary(),
+            false => None,
+        };
+        let binary = binary_imf.is_some();
+        let imf = binary_imf.unwrap_or_else(|| message.to_imf().into_bytes());
+
+        if let Some(max_size) = self.m_capabilities.get_max_message_size() {
+            let size = imf.len() as u64;
+            if size > max_size {
+                return Err(Error::MessageBuild(format!("Message size {size} exceeds the server limit of {max_size} bytes")));
+            }
+        }
+
+        let parameters = Self::mail_parameters(binary, smtputf8);
+
+        let mut report = if self.m_capabilities.supports_pipelining() {
+            self.send_envelope_pipelined(message, &parameters, !use_bdat).await?
+        } else {
+            self.send_envelope(message, &parameters, !use_bdat).await?
+        };
 
-        self.send_mail_from_cmd(&message.from).await?;
-        for to in message.to.iter() {
-            self.send_rcpt_to_cmd(to).await?;
+        if use_bdat {
+            self.send_message_bdat(imf.chunks(DATA_BLOCK_SIZE), binary).await?;
+        } else {
+            self.send_message_imf(&imf).await?;
         }
 
-        self.send_data_cmd().await?;
-        self.send_message_imf(&message).await
+        report.set_message_id(&message.message_id);
+        Ok(report)
+    }
+
+    /// The parameters of MAIL FROM: BODY=BINARYMIME for content with binary
+    /// parts (RFC 3030) and SMTPUTF8 for non-ASCII addresses (RFC 6531).
+    /// Content sent otherwise is 7bit, so it needs no BODY parameter.
+    fn mail_parameters(binary: bool, smtputf8: bool) -> Vec<&'static str> {
+        let mut parameters = Vec::new();
+        if binary {
+            parameters.push("BODY=BINARYMIME");
+        }
+        if smtputf8 {
+            parameters.push("SMTPUTF8");
+        }
+        parameters
+    }
+
+    /// Sends MAIL and each RCPT waiting for every reply, then DATA if
+    /// `send_data` is set and the accepted recipients satisfy the recipient
+    /// policy.
+    async fn send_envelope(&mut self, message: &SmtpMessage, parameters: &[&str], send_data: bool) -> Result<SendReport, Error> {
+        self.send_mail_from_cmd(message.get_sender(), parameters).await?;
+
+        let mut report = SendReport::default();
+        for to in message.get_recipients() {
+            let response = self.send_rcpt_to_cmd(to).await?;
+            report.add_recipient(to, &response);
+        }
+
+        if let Err(err) = report.check(self.m_recipient_policy) {
+            self.send_rset_cmd().await?;
+            return Err(err);
+        }
+
+        if send_data {
+            self.send_data_cmd().await?;
+        }
+        Ok(report)
+    }
+
+    /// Sends MAIL, every RCPT and, if `send_data` is set, DATA without
+    /// waiting for each reply: commands go out in batches, and the replies to
+    /// a batch, which come back in the same order, are read before the next
+    /// one is written (RFC 2920).
+    ///
+    /// All replies are consumed even after a failure so none is left queued
+    /// for a later command.
+    async fn send_envelope_pipelined(&mut self, message: &SmtpMessage, parameters: &[&str], send_data: bool) -> Result<SendReport, Error> {
+        let recipients = message.get_recipients();
+        let mut commands = vec![(SmtpPhase::Mail, format!("{MailFrom} {}\r\n", Self::mail_from_arg(message.get_sender(), parameters)))];
+        commands.extend(recipients.iter().map(|to| (SmtpPhase::Rcpt, format!("{RcptTo} <{to}>\r\n"))));
+        if send_data {
+            commands.push((SmtpPhase::DataInitiation, format!("{Data}\r\n")));
+        }
+
+        let mut responses = Vec::with_capacity(commands.len());
+        for batch in commands.chunks(PIPELINING_BATCH_SIZE) {
+            let batch_commands: String = batch.iter().map(|(_, command)| command.as_str()).collect();
+            self.trace(&batch_commands);
+            self.m_phase = batch[0].0;
+            self.write(batch_commands.as_bytes()).await?;
+
+            for (phase, _) in batch {
+                self.m_phase = *phase;
+                responses.push(self.handle_response().await?);
+            }
+        }
+        let mail_result = responses.remove(0).status_should_be(Some(&MailFrom), SmtpStatus::PositiveCompletion);
+        let mut responses = responses.into_iter();
+        let mail_accepted = mail_result.is_ok();
+
+        let mut report = SendReport::default();
+        for (to, response) in recipients.iter().zip(responses.by_ref()) {
+            report.add_recipient(to, &response);
+        }
+        let data_response = responses.next();
+
+        match mail_result.and_then(|()| report.check(self.m_recipient_policy)) {
+            Ok(()) => {
+                if let Some(data_response) = data_response {
+                    data_response.status_should_be(Some(&Data), SmtpStatus::PositiveIntermediate)?;
+                }
+            },
+            Err(err) => {
+                if data_response.is_some_and(|response| response.get_status() == SmtpStatus::PositiveIntermediate) {
+                    // The server is waiting for the content of a transaction we
+                    // gave up on; only dropping the connection aborts it.
+                    self.m_stream.close();
+                } else if mail_accepted {
+                    self.send_rset_cmd().await?;
+                }
+                return Err(err);
+            },
+        }
+
+        Ok(report)
+    }
+
+    /// Transfers the content with BDAT (RFC 3030) block by block, in chunks of
+    /// at most the chunk size, the last one flagged LAST. Unlike DATA there is
+    /// no dot-stuffing or terminating "."; line endings are normalized to CRLF
+    /// except in BINARYMIME content, which goes out as is: its text parts
+    /// have CRLF line endings already, and binary parts must not change.
+    async fn send_message_bdat<'a>(&mut self, blocks: impl Iterator<Item = &'a [u8]>, binary: bool) -> Result<usize, Error> {
+        let mut encoder = DataEncoder::line_endings_only();
+        let mut pending = Vec::new();
+        let mut request = 0;
+
+        for block in blocks.map(Some).chain([None]) {
+            match (block, binary) {
+                (Some(block), true) => pending.extend_from_slice(block),
+                (Some(block), false) => pending.extend(encoder.encode(block)),
+                (None, true) => {},
+                (None, false) => pending.extend(encoder.finish()),
+            }
+
+            // A full chunk is held back until more content follows, as it
+            // may be the last one.
+            while pending.len() > self.m_chunk_size {
+                let chunk: Vec<u8> = pending.drain(..self.m_chunk_size).collect();
+                request += self.send_bdat_chunk(&chunk, false).await?;
+            }
+        }
+
+        request += self.send_bdat_chunk(&pending, true).await?;
+        Ok(request)
+    }
+
+    async fn send_bdat_chunk(&mut self, chunk: &[u8], is_last: bool) -> Result<usize, Error> {
+        let command = match is_last {
+            true => format!("{Bdat} {} LAST\r\n", chunk.len()),
+            false => format!("{Bdat} {}\r\n", chunk.len()),
+        };
+        self.trace(&command);
+
+        self.m_phase = match is_last {
+            true => SmtpPhase::DataTermination,
+            false => SmtpPhase::DataBlock,
+        };
+        let request = self.write(&[command.as_bytes(), chunk].concat()).await?;
+
+        if let Err(err) = self.handle_response().await?.status_should_be(Some(&Bdat), SmtpStatus::PositiveCompletion) {
+            // A failed chunk leaves the transaction open, a failed last one ends it.
+            if !is_last {
+                self.send_rset_cmd().await?;
+            }
+            return Err(err);
+        }
+
+        Ok(request)
+    }
+
+    /// Replaces the connection with a fresh one, restoring encryption and
+    /// authentication as they were on the old connection.
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        let was_encrypted = self.is_encrypted();
+        self.m_stream.close();
+
+        let mut session = Self::connect_with_timeouts(&self.m_server, self.m_security, self.m_tls_config.clone(), self.m_timeouts.clone()).await?;
+        session.m_retry_policy = self.m_retry_policy.clone();
+        session.m_recipient_policy = self.m_recipient_policy;
+        session.m_chunk_size = self.m_chunk_size;
+        session.m_trace = self.m_trace;
+
+        if was_encrypted && !session.is_encrypted() {
+            session.encrypt_connection().await?;
+        }
+
+        session.m_allow_plaintext_auth = self.m_allow_plaintext_auth;
+
+        if let (Some(credentials), Some(mechanism)) = (&self.m_credentials, &self.m_auth_mechanism) {
+            session.authenticate_mechanism(credentials, mechanism).await?;
+        }
+
+        *self = session;
+        Ok(())
     }
 
-    
     async fn send_ehlo_cmd(&mut self) -> Result<usize, Error> {
         let request = self.send_cmd_with_arg(Ehlo, "localhost").await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+        let response = self.handle_response().await?;
+        response.status_should_be(Some(&Ehlo), SmtpStatus::PositiveCompletion)?;
+
+        self.m_capabilities = ServerCapabilities::from_ehlo_lines(&response.get_text_lines());
 
         Ok(request)
     }
 
     async fn send_starttls_cmd(&mut self) -> Result<usize, Error> {
         let request = self.send_cmd(StartTls).await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+        self.handle_response().await?.status_should_be(Some(&StartTls), SmtpStatus::PositiveCompletion)?;
 
         Ok(request)
     }
 
     async fn send_register_cmd(&mut self, encoded_auth: &str) -> Result<usize, Error> {
         let request = self.send_cmd_with_arg(Register, encoded_auth).await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+        self.handle_response().await?.status_should_be(Some(&Register), SmtpStatus::PositiveCompletion)?;
 
         Ok(request)
     }
 
     async fn send_auth_plain_cmd(&mut self, encoded_auth: &str) -> Result<usize, Error> {
         let request = self.send_cmd_with_arg(AuthPlain, encoded_auth).await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+        self.handle_response().await?.status_should_be(Some(&AuthPlain), SmtpStatus::PositiveCompletion)?;
 
         Ok(request)
     }
 
-    async fn send_mail_from_cmd(&mut self, from: &str) -> Result<usize, Error> {
-        let arg = format!("<{from}>");
-        let request = self.send_cmd_with_arg(MailFrom, &arg).await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+    /// Runs the LOGIN exchange: the server prompts for the username and then
+    /// the password with 334 replies, each answered with a base64 line.
+    async fn send_auth_login_cmd(&mut self, username: &str, password: &str) -> Result<usize, Error> {
+        let mut request = self.send_cmd(AuthLogin).await?;
+        self.handle_response().await?.status_should_be(Some(&AuthLogin), SmtpStatus::PositiveIntermediate)?;
+
+        request += self.send_line(&base64::encode(username)).await?;
+        self.handle_response().await?.status_should_be(Some(&AuthLogin), SmtpStatus::PositiveIntermediate)?;
+
+        request += self.send_line(&base64::encode(password)).await?;
+        self.handle_response().await?.status_should_be(Some(&AuthLogin), SmtpStatus::PositiveCompletion)?;
 
         Ok(request)
     }
 
-    async fn send_rcpt_to_cmd(&mut self, to: &str) -> Result<usize, Error> {
-        let arg = format!("<{to}>");
-        let request = self.send_cmd_with_arg(RcptTo, &arg).await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+    fn mail_from_arg(from: &str, parameters: &[&str]) -> String {
+        let mut arg = format!("<{from}>");
+        for parameter in parameters {
+            arg += &format!(" {parameter}");
+        }
+        arg
+    }
+
+    async fn send_mail_from_cmd(&mut self, from: &str, parameters: &[&str]) -> Result<usize, Error> {
+        let arg = Self::mail_from_arg(from, parameters);
+        let request = self.send_cmd_with_arg(MailFrom, &arg).await?;
+        self.handle_response().await?.status_should_be(Some(&MailFrom), SmtpStatus::PositiveCompletion)?;
 
         Ok(request)
     }
 
+    /// Returns the reply unchecked: a rejected recipient does not end the
+    /// transaction by itself.
+    async fn send_rcpt_to_cmd(&mut self, to: &str) -> Result<SmtpResponse, Error> {
+        let arg = format!("<{to}>");
+        self.send_cmd_with_arg(RcptTo, &arg).await?;
+        self.handle_response().await
+    }
+
     async fn send_data_cmd(&mut self) -> Result<usize, Error> {
         let request = self.send_cmd(Data).await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveIntermediate)?;
+        self.handle_response().await?.status_should_be(Some(&Data), SmtpStatus::PositiveIntermediate)?;
+
+        Ok(request)
+    }
+
+    async fn send_rset_cmd(&mut self) -> Result<usize, Error> {
+        let request = self.send_cmd(Rset).await?;
+        self.handle_response().await?.status_should_be(Some(&Rset), SmtpStatus::PositiveCompletion)?;
 
         Ok(request)
     }
 
     pub async fn send_quit_cmd(&mut self) -> Result<usize, Error> {
         let request = self.send_cmd(Quit).await?;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
+        self.handle_response().await?.status_should_be(Some(&Quit), SmtpStatus::PositiveCompletion)?;
 
         Ok(request)
     }
 
-    async fn send_message_imf(&mut self, message: &SmtpMessage) -> Result<usize, Error> {
-        let message = format!("{}{}", message.to_imf(),  Dot);
-        print!("{}", message);
-        let request = self.m_stream.write(message.as_bytes()).await;
-        self.handle_response().await?.status_should_be(SmtpStatus::PositiveCompletion)?;
-        request
+    /// Sends the content after DATA block by block, dot-stuffed and with CRLF
+    /// line endings, followed by the "." line.
+    async fn send_message_imf(&mut self, imf: &[u8]) -> Result<usize, Error> {
+        let mut encoder = DataEncoder::new();
+        let mut request = 0;
+
+        self.m_phase = SmtpPhase::DataBlock;
+        for block in imf.chunks(DATA_BLOCK_SIZE) {
+            request += self.write(&encoder.encode(block)).await?;
+        }
+
+        let end = encoder.finish();
+        self.m_phase = SmtpPhase::DataTermination;
+        request += self.write(&end).await?;
+
+        self.handle_response().await?.status_should_be(Some(&Dot), SmtpStatus::PositiveCompletion)?;
+        Ok(request)
     }
 
 
     async fn send_cmd(&mut self, cmd: SmtpCommand) -> Result<usize, Error> {
         let command = format!("{cmd}\r\n");
-        print!("{}", command);
-        self.m_stream.write(command.as_bytes()).await
+        self.trace(&command);
+        self.m_phase = SmtpPhase::from(cmd);
+        self.write(command.as_bytes()).await
     }
 
     async fn send_cmd_with_arg(&mut self, cmd: SmtpCommand, arg: &str) -> Result<usize, Error> {
         let command = format!("{cmd} {arg}\r\n");
-        print!("{}", command);
-        self.m_stream.write(command.as_bytes()).await
+        match cmd {
+            // Keep the mechanism name, hide the initial response.
+            Auth => match arg.split_once(' ') {
+                Some((name, _)) => self.trace(&format!("{cmd} {name} <redacted>\r\n")),
+                None => self.trace(&command),
+            },
+            AuthPlain | Register => self.trace(&format!("{cmd} <redacted>\r\n")),
+            _ => self.trace(&command),
+        }
+        self.m_phase = SmtpPhase::from(cmd);
+        self.write(command.as_bytes()).await
+    }
+
+    async fn send_line(&mut self, line: &str) -> Result<usize, Error> {
+        // Only ever used for responses to AUTH challenges.
+        self.trace("<redacted>\r\n");
+        let line = format!("{line}\r\n");
+        self.write(line.as_bytes()).await
+    }
+
+    /// Writes with the timeout of the current phase. After a timeout the
+    /// state of the dialogue is unknown, so the connection is closed.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match timeout(self.m_timeouts.get(self.m_phase), self.m_stream.write(buf)).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.m_stream.close();
+                Err(self.m_timeouts.error(self.m_phase))
+            },
+        }
     }
 
 
     async fn handle_response(&mut self) -> Result<SmtpResponse, Error> {
         let smtp_response_builder = SmtpResponseBuilder::new();
 
-        match smtp_response_builder.build(&self.m_stream.read().await?) {
-            Ok(smtp_response) => {
-                print!("{}", smtp_response.get_raw_response());
-                Ok(smtp_response)
+        let reply = match timeout(self.m_timeouts.get(self.m_phase), self.m_stream.read()).await {
+            Ok(reply) => reply?,
+            Err(_) => {
+                self.m_stream.close();
+                return Err(self.m_timeouts.error(self.m_phase));
             },
-            Err(e) => Err(e),
+        };
+
+        self.trace(&reply);
+        smtp_response_builder.build(&reply)
+    }
+
+    fn trace(&self, text: &str) {
+        if let Some(trace) = self.m_trace {
+            trace(text);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_stream::test_pki::TestPki;
+    use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio::time::Duration;
+
+    /// Serves one connection: sends `greeting`, then answers each command
+    /// line with the reply of the first rule whose prefix matches. An empty
+    /// reply leaves the command unanswered. The message content after a 354
+    /// is collected up to the final "." line, which is answered by the "."
+    /// rule; the content of a BDAT command is received as one more entry,
+    /// each byte as the char of the same value.
+    /// Returns the address to connect to and, once the client hangs
+    /// up, every line received.
+    async fn scripted_server(greeting: &'static str, rules: Vec<(&'static str, &'static str)>) -> (String, JoinHandle<Vec<String>>) {
+        dialogue_server(greeting, move |command| {
+            rules.iter()
+                .find(|(prefix, _)| command.starts_with(prefix))
+                .map(|(_, reply)| reply.to_string())
+                .or_else(|| Some("500 5.5.2 Unrecognized command\r\n".to_string()))
+        }).await
+    }
+
+    /// Like `scripted_server`, but each reply is computed from the command
+    /// line, for exchanges that depend on what the client sent. `None` hangs
+    /// up without answering.
+    async fn dialogue_server<F>(greeting: &'static str, mut reply: F) -> (String, JoinHandle<Vec<String>>)
+    where
+        F: FnMut(&str) -> Option<String> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, greeting, &mut reply).await
+        });
+
+        (address, server)
+    }
+
+    type Reply = Box<dyn FnMut(&str) -> Option<String> + Send>;
+
+    /// Serves one connection per reply function in turn, each as
+    /// `dialogue_server` does, and returns the lines received on each.
+    async fn redial_server(greeting: &'static str, replies: Vec<Reply>) -> (String, JoinHandle<Vec<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for mut reply in replies {
+                let (stream, _) = listener.accept().await.unwrap();
+                received.push(serve(stream, greeting, &mut reply).await);
+            }
+            received
+        });
+
+        (address, server)
+    }
+
+    async fn serve<S>(stream: S, greeting: &str, reply: &mut (dyn FnMut(&str) -> Option<String> + Send)) -> Vec<String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let mut received = Vec::new();
+        let mut in_data = false;
+
+        writer.write_all(greeting.as_bytes()).await.unwrap();
+
+        let mut line = String::new();
+        while reader.read_line(&mut line).await.unwrap_or(0) > 0 {
+            let command = line.trim_end_matches("\r\n").to_string();
+            line.clear();
+            received.push(command.clone());
+
+            if in_data && command != "." {
+                continue;
+            }
+
+            if let Some(size) = command.strip_prefix("BDAT ").and_then(|args| args.split(' ').next()) {
+                let mut chunk = vec![0; size.parse().unwrap()];
+                reader.read_exact(&mut chunk).await.unwrap();
+                received.push(chunk.into_iter().map(char::from).collect());
+            }
+
+            let Some(reply) = reply(&command) else {
+                break;
+            };
+            in_data = reply.starts_with("354");
+            if writer.write_all(reply.as_bytes()).await.is_err() {
+                break;
+            }
+
+            // A reply ending on a continuation line is cut off by hanging up.
+            if reply.lines().last().is_some_and(|line| line.as_bytes().get(3) == Some(&b'-')) {
+                break;
+            }
+        }
+
+        received
+    }
+
+    fn message() -> SmtpMessage {
+        SmtpMessage::builder()
+            .from("alice@example.com")
+            .to("bob@example.com")
+            .subject("Hello")
+            .body("Hi Bob")
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 8BITMIME\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", "250 2.1.5 OK\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+            (".", "250 2.0.0 Queued\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        assert!(session.get_capabilities().supports_8bitmime());
+        let message = message();
+        let message_id = message.message_id.clone();
+        let report = session.send_message(message).await.unwrap();
+        assert_eq!(report.get_message_id(), message_id);
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received[1], "MAIL FROM: <alice@example.com>");
+        assert_eq!(received[2], "RCPT TO: <bob@example.com>");
+        assert!(received.contains(&format!("Message-ID: <{}>", message_id)));
+        assert_eq!(received.last().map(String::as_str), Some("."));
+    }
+
+    #[tokio::test]
+    async fn test_cc_and_bcc_are_recipients() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250 mx.example.com\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", "250 2.1.5 OK\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+            (".", "250 2.0.0 Queued\r\n"),
+        ]).await;
+
+        let message = SmtpMessage::builder()
+            .from("alice@example.com")
+            .to("bob@example.com")
+            .cc("carol@example.com")
+            .bcc("dave@example.com")
+            .subject("Hello")
+            .body("Hi Bob")
+            .build()
+            .unwrap();
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let report = session.send_message(message).await.unwrap();
+        assert_eq!(report.get_accepted().len(), 3);
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received[2..5], ["RCPT TO: <bob@example.com>", "RCPT TO: <carol@example.com>", "RCPT TO: <dave@example.com>"]);
+
+        let content = &received[received.iter().position(|line| line == "DATA").unwrap() + 1..];
+        assert!(content.contains(&"Cc: carol@example.com".to_string()));
+        assert!(!content.iter().any(|line| line.contains("dave")));
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_envelope_is_sent_in_one_go() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Replies to MAIL, RCPT and DATA only once all three were received,
+        // which would stall a client waiting after each command.
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+
+            writer.write_all(b"220 mx.example.com ESMTP\r\n").await.unwrap();
+            lines.next_line().await.unwrap();
+            writer.write_all(b"250-mx.example.com\r\n250 PIPELINING\r\n").await.unwrap();
+
+            let mut envelope = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                envelope.push(line.clone());
+                if line == "DATA" {
+                    break;
+                }
+            }
+            writer.write_all(b"250 2.1.0 OK\r\n250 2.1.5 OK\r\n550 5.1.1 No such user\r\n554 5.5.1 No valid recipients\r\n").await.unwrap();
+
+            envelope.push(lines.next_line().await.unwrap().unwrap());
+            writer.write_all(b"250 2.0.0 Flushed\r\n").await.unwrap();
+            envelope
+        });
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_recipient_policy(RecipientPolicy::AllOrNothing);
+        let mut message = message();
+        message.to.push("nobody@example.com".to_string());
+
+        let result = session.send_message(message).await;
+        let Err(Error::SmtpReply(reply)) = result else { panic!("unexpected result {:?}", result); };
+        assert_eq!(reply.command, Some(RcptTo));
+        assert_eq!(reply.code, 550);
+
+        assert_eq!(server.await.unwrap(), vec![
+            "MAIL FROM: <alice@example.com>",
+            "RCPT TO: <bob@example.com>",
+            "RCPT TO: <nobody@example.com>",
+            "DATA",
+            "RSET",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_failure_after_354_drops_the_transaction() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 PIPELINING\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO: <bob", "250 2.1.5 OK\r\n"),
+            ("RCPT TO:", "550 5.1.1 No such user\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_recipient_policy(RecipientPolicy::AllOrNothing);
+        let mut message = message();
+        message.to.push("nobody@example.com".to_string());
+
+        assert!(session.send_message(message).await.is_err());
+        assert!(!server.await.unwrap().contains(&".".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_partially_rejected_recipients_are_reported() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250 mx.example.com\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO: <bob", "250 2.1.5 OK\r\n"),
+            ("RCPT TO:", "550 5.1.1 No such user\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+            (".", "250 2.0.0 Queued\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mut message = message();
+        message.to.push("nobody@example.com".to_string());
+
+        let report = session.send_message(message).await.unwrap();
+        assert_eq!(report.get_accepted().iter().map(|status| status.address.as_str()).collect::<Vec<_>>(), vec!["bob@example.com"]);
+        assert_eq!(report.get_rejected().len(), 1);
+        assert_eq!(report.get_rejected()[0].address, "nobody@example.com");
+        assert_eq!(report.get_rejected()[0].code, 550);
+        drop(session);
+
+        assert_eq!(server.await.unwrap().last().map(String::as_str), Some("."));
+    }
+
+    #[tokio::test]
+    async fn test_all_or_nothing_resets_the_transaction() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250 mx.example.com\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO: <bob", "250 2.1.5 OK\r\n"),
+            ("RCPT TO:", "550 5.1.1 No such user\r\n"),
+            ("RSET", "250 2.0.0 OK\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_recipient_policy(RecipientPolicy::AllOrNothing);
+        let mut message = message();
+        message.to.push("nobody@example.com".to_string());
+
+        let result = session.send_message(message).await;
+        assert!(matches!(result, Err(Error::SmtpReply(reply)) if reply.code == 550));
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received.last().map(String::as_str), Some("RSET"));
+        assert!(!received.contains(&"DATA".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_bdat_transfer_in_chunks() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250-PIPELINING\r\n250 CHUNKING\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", "250 2.1.5 OK\r\n"),
+            ("BDAT", "250 2.0.0 OK\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_chunk_size(32);
+        let mut message = message();
+        message.body = "Grüße\r\n.\r\nno dot-stuffing here".to_string();
+        let imf = message.to_imf() + "\r\n";
+
+        session.send_message(message).await.unwrap();
+        drop(session);
+
+        let received = server.await.unwrap();
+        // The text is sent quoted-printable, so there is no 8-bit content to declare.
+        assert_eq!(received[1], "MAIL FROM: <alice@example.com>");
+        assert!(!received.contains(&"DATA".to_string()));
+
+        let commands: Vec<&String> = received.iter().filter(|line| line.starts_with("BDAT ")).collect();
+        let chunks: Vec<&String> = received.iter().skip_while(|line| !line.starts_with("BDAT ")).skip(1).step_by(2).collect();
+        assert_eq!(commands.len(), imf.len().div_ceil(32));
+        assert!(commands.last().unwrap().ends_with(" LAST"));
+        assert!(commands[..commands.len() - 1].iter().all(|command| command.as_str() == "BDAT 32"));
+        assert_eq!(chunks.into_iter().cloned().collect::<String>(), imf);
+    }
+
+    #[tokio::test]
+    async fn test_binarymime_transfer_with_bdat() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250-CHUNKING\r\n250 BINARYMIME\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", "250 2.1.5 OK\r\n"),
+            ("BDAT", "250 2.0.0 OK\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_chunk_size(64);
+        let data = b"\x00\x01\r\n\n\xff\xfe\r".repeat(20);
+        let message = SmtpMessage::builder()
+            .from("alice@example.com")
+            .to("bob@example.com")
+            .subject("Report")
+            .body("Grüße\nBob")
+            .attachment(data.as_slice(), "report.bin", Some("application/octet-stream"))
+            .build()
+            .unwrap();
+        let imf = message.to_imf_binary().unwrap();
+        session.send_message(message).await.unwrap();
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received[1], "MAIL FROM: <alice@example.com> BODY=BINARYMIME");
+
+        let chunks: String = received.iter().skip_while(|line| !line.starts_with("BDAT ")).skip(1).step_by(2).cloned().collect();
+        let as_chars = |bytes: &[u8]| bytes.iter().copied().map(char::from).collect::<String>();
+        assert_eq!(chunks, as_chars(&imf));
+        assert!(chunks.contains(&as_chars("Content-Transfer-Encoding: 8bit\r\n\r\nGrüße\r\nBob\r\n".as_bytes())));
+        assert!(chunks.contains(&format!("Content-Transfer-Encoding: binary\r\n\r\n{}\r\n", as_chars(&data))));
+    }
+
+    #[tokio::test]
+    async fn test_non_ascii_address_requires_smtputf8() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 8BITMIME\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mut non_ascii = message();
+        non_ascii.to = vec!["jürgen@example.com".to_string()];
+        assert!(non_ascii.requires_smtputf8());
+        assert_eq!(session.send_message(non_ascii).await,
+            Err(Error::MessageBuild("Non-ASCII addresses require SMTPUTF8, which the server does not support".to_string())));
+        drop(session);
+        assert_eq!(server.await.unwrap().len(), 1);
+
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250-8BITMIME\r\n250 SMTPUTF8\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", "250 2.1.5 OK\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+            (".", "250 2.0.0 Queued\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mut message = message();
+        message.to = vec!["Jürgen <jürgen@example.com>".to_string()];
+        session.send_message(message).await.unwrap();
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received[1..3], ["MAIL FROM: <alice@example.com> SMTPUTF8", "RCPT TO: <jürgen@example.com>"]);
+        assert!(received.contains(&"To: =?utf-8?Q?J=C3=BCrgen?= <jürgen@example.com>".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_data_is_dot_stuffed() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250 mx.example.com\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", "250 2.1.5 OK\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+            (".", "250 2.0.0 Queued\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mut message = message();
+        message.body = "first\n.\n.hidden\nlast".to_string();
+
+        session.send_message(message).await.unwrap();
+        drop(session);
+
+        let received = server.await.unwrap();
+        let body: Vec<&str> = received.iter().skip_while(|line| line.as_str() != "").map(String::as_str).collect();
+        assert_eq!(body, vec!["", "first", "..", "..hidden", "last", "."]);
+    }
+
+    #[tokio::test]
+    async fn test_greeting_timeout() {
+        let (address, _server) = scripted_server("", vec![]).await;
+        let timeouts = Timeouts::default().greeting(Duration::from_millis(50));
+
+        let result = SmtpSession::connect_with_timeouts(&address, ConnectionSecurity::Plain, TlsConfig::default(), timeouts).await;
+        assert_eq!(result.err(), Some(Error::Timeout("Greeting timed out after 50ms".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_timeout_names_phase() {
+        let (address, _server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250 mx.example.com\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", ""),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_retry_policy(RetryPolicy::no_retry());
+        session.set_timeouts(Timeouts::default().rcpt(Duration::from_millis(50)));
+
+        let result = session.send_message(message()).await;
+        assert_eq!(result, Err(Error::Timeout("RCPT timed out after 50ms".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_oauth2_error_challenge() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH OAUTHBEARER\r\n"),
+            ("AUTH OAUTHBEARER ", "334 eyJzdGF0dXMiOiJpbnZhbGlkX3Rva2VuIiwic2NvcGUiOiJodHRwczovL21haWwuZXhhbXBsZS5jb20vIn0=\r\n"),
+            ("AQ==", "535 5.7.8 Authentication credentials invalid\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let result = session.authenticate_oauth2("alice@example.com", "expired-token").await;
+        drop(session);
+
+        assert_eq!(result, Err(Error::OAuth2(error_handler::OAuth2Error {
+            status: "invalid_token".to_string(),
+            scope: Some("https://mail.example.com/".to_string()),
+            ..Default::default()
+        })));
+
+        // The client acknowledges the error challenge with a lone %x01.
+        assert_eq!(server.await.unwrap().last().map(String::as_str), Some("AQ=="));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_auto_picks_the_strongest_mechanism() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH PLAIN LOGIN CRAM-MD5 XOAUTH2\r\n"),
+            ("AUTH CRAM-MD5", "334 PDEyMzQ1QGV4YW1wbGUuY29tPg==\r\n"),
+            ("YWxpY2Ug", "235 2.7.0 Authentication successful\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mechanism = session.authenticate_auto(&Credentials::password("alice", "secret")).await;
+        drop(session);
+
+        assert_eq!(mechanism, Ok(AuthMechanism::CramMd5));
+        assert_eq!(server.await.unwrap()[1..], ["AUTH CRAM-MD5", "YWxpY2UgMTZjYjk2MjY2ZDlhZGIyMDVlZDZjOTE3M2Y0M2NhOTg="]);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_auto_refuses_plaintext_on_unencrypted_connection() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH PLAIN LOGIN\r\n"),
+            ("AUTH", "235 2.7.0 Authentication successful\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mechanism = session.authenticate_auto(&Credentials::password("alice", "secret")).await;
+        drop(session);
+
+        assert_eq!(mechanism, Err(Error::Sasl("Refusing plaintext authentication on an unencrypted connection".to_string())));
+        assert!(!server.await.unwrap().iter().any(|line| line.starts_with("AUTH")));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_auto_allows_plaintext_when_opted_in() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH LOGIN PLAIN\r\n"),
+            ("AUTH PLAIN", "235 2.7.0 Authentication successful\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_allow_plaintext_auth(true);
+        let mechanism = session.authenticate_auto(&Credentials::password("alice", "secret")).await;
+        drop(session);
+
+        assert_eq!(mechanism, Ok(AuthMechanism::Plain));
+        assert_eq!(server.await.unwrap()[1..], ["AUTH PLAIN AGFsaWNlAHNlY3JldA=="]);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_falls_back_to_login() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH CRAM-MD5 LOGIN\r\n"),
+            ("AUTH LOGIN", "334 VXNlcm5hbWU6\r\n"),
+            ("YWxpY2U=", "334 UGFzc3dvcmQ6\r\n"),
+            ("c2VjcmV0", "235 2.7.0 Authentication successful\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        assert!(session.authenticate("alice", "secret").await.is_ok());
+        drop(session);
+
+        assert_eq!(server.await.unwrap()[1..], ["AUTH LOGIN", "YWxpY2U=", "c2VjcmV0"]);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_prefers_plain_over_login() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH LOGIN PLAIN\r\n"),
+            ("AUTH PLAIN", "235 2.7.0 Authentication successful\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        assert!(session.authenticate("alice", "secret").await.is_ok());
+        drop(session);
+
+        assert_eq!(server.await.unwrap()[1..], ["AUTH PLAIN AGFsaWNlAHNlY3JldA=="]);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_without_plain_or_login() {
+        let (address, _server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH CRAM-MD5\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        assert_eq!(session.authenticate("alice", "secret").await,
+            Err(Error::Sasl("Neither AUTH PLAIN nor AUTH LOGIN is supported by the server".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_login_rejected_password() {
+        let (address, _server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH LOGIN\r\n"),
+            ("AUTH LOGIN", "334 VXNlcm5hbWU6\r\n"),
+            ("YWxpY2U=", "334 UGFzc3dvcmQ6\r\n"),
+            ("", "535 5.7.8 Authentication credentials invalid\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let Err(Error::SmtpReply(reply)) = session.authenticate("alice", "wrong").await else {
+            panic!("the rejected password should be reported as an SMTP reply");
+        };
+        assert_eq!(reply.code, 535);
+        assert_eq!(reply.command, Some(AuthLogin));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_with_runs_the_challenge_exchange() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH CRAM-MD5\r\n"),
+            ("AUTH CRAM-MD5", "334 PDEyMzQ1QGV4YW1wbGUuY29tPg==\r\n"),
+            ("YWxpY2Ug", "235 2.7.0 Authentication successful\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        assert!(session.authenticate_with(&mut CramMd5::new("alice", "secret")).await.is_ok());
+        drop(session);
+
+        assert_eq!(server.await.unwrap()[1..], ["AUTH CRAM-MD5", "YWxpY2UgMTZjYjk2MjY2ZDlhZGIyMDVlZDZjOTE3M2Y0M2NhOTg="]);
+    }
+
+    #[tokio::test]
+    async fn test_scram_signature_mismatch_cancels_the_exchange() {
+        let (address, server) = dialogue_server("220 mx.example.com ESMTP\r\n", |command| {
+            let reply = if command.starts_with("EHLO") {
+                "250-mx.example.com\r\n250 AUTH SCRAM-SHA-256\r\n".to_string()
+            } else if let Some(client_first) = command.strip_prefix("AUTH SCRAM-SHA-256 ") {
+                let client_first = String::from_utf8(base64::decode(client_first).unwrap()).unwrap();
+                let (_, client_nonce) = client_first.split_once(",r=").unwrap();
+                let server_first = format!("r={}server,s=c2FsdA==,i=4096", client_nonce);
+                format!("334 {}\r\n", base64::encode(&server_first))
+            } else if command == "*" {
+                "501 5.7.0 Authentication cancelled\r\n".to_string()
+            } else {
+                // Answers the client proof with a signature the client cannot
+                // have computed.
+                format!("334 {}\r\n", base64::encode(&format!("v={}", base64::encode_bytes(&[0; 32]))))
+            };
+            Some(reply)
+        }).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let result = session.authenticate_with(&mut Scram::sha256("alice", "secret")).await;
+        drop(session);
+
+        assert_eq!(result, Err(Error::Sasl("SCRAM: server signature mismatch".to_string())));
+        let received = server.await.unwrap();
+        assert_eq!(received.len(), 4);
+        assert!(base64::decode(&received[2]).unwrap().starts_with(b"c=biws,r="));
+        assert_eq!(received[3], "*");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_exchange_keeps_the_session_usable() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 AUTH CRAM-MD5\r\n"),
+            ("AUTH CRAM-MD5", "334 not base64\r\n"),
+            ("*", "501 5.7.0 Authentication cancelled\r\n"),
+            ("QUIT", "221 2.0.0 Bye\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let result = session.authenticate_with(&mut CramMd5::new("alice", "secret")).await;
+        assert_eq!(result, Err(Error::SmtpResponse("Invalid base64 data: not base64".to_string())));
+        assert!(session.send_quit_cmd().await.is_ok());
+        drop(session);
+
+        assert_eq!(server.await.unwrap()[1..], ["AUTH CRAM-MD5", "*", "QUIT"]);
+    }
+
+    /// Answers like the LOGIN server of `test_authenticate_falls_back_to_login`
+    /// and accepts any message, except that it hangs up on `drop_at`.
+    fn login_server(drop_at: &'static str) -> Reply {
+        login_server_with(drop_at, None)
+    }
+
+    /// Like `login_server`, but answers `prefix` with `reply` instead.
+    fn login_server_with(prefix: &'static str, reply: Option<&'static str>) -> Reply {
+        Box::new(move |command| {
+            let reply = match command {
+                _ if !prefix.is_empty() && command.starts_with(prefix) => reply?,
+                _ if command.starts_with("EHLO") => "250-mx.example.com\r\n250 AUTH LOGIN\r\n",
+                "AUTH LOGIN" => "334 VXNlcm5hbWU6\r\n",
+                "YWxpY2U=" => "334 UGFzc3dvcmQ6\r\n",
+                "c2VjcmV0" => "235 2.7.0 Authentication successful\r\n",
+                "DATA" => "354 Go ahead\r\n",
+                "." => "250 2.0.0 Queued\r\n",
+                _ => "250 OK\r\n",
+            };
+            Some(reply.to_string())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_trace_redacts_credentials_and_leaves_out_content() {
+        static TRACE: std::sync::Mutex<String> = std::sync::Mutex::new(String::new());
+        let (address, _server) = dialogue_server("220 mx.example.com ESMTP\r\n", login_server("")).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_trace(Some(|text| TRACE.lock().unwrap().push_str(text)));
+        session.authenticate("alice", "secret").await.unwrap();
+        session.send_message(message()).await.unwrap();
+
+        let trace = TRACE.lock().unwrap();
+        assert!(trace.starts_with("AUTH LOGIN\r\n334 VXNlcm5hbWU6\r\n<redacted>\r\n334 UGFzc3dvcmQ6\r\n<redacted>\r\n235 "));
+        assert!(trace.contains("MAIL FROM: <alice@example.com>\r\n250 OK\r\n"));
+        assert!(trace.ends_with("DATA\r\n354 Go ahead\r\n250 2.0.0 Queued\r\n"));
+        assert!(!trace.contains("YWxpY2U=") && !trace.contains("c2VjcmV0") && !trace.contains("Hi Bob"));
+    }
+
+    #[tokio::test]
+    async fn test_resend_after_dropped_connection_reauthenticates() {
+        let (address, server) = redial_server("220 mx.example.com ESMTP\r\n", vec![
+            login_server("MAIL FROM:"),
+            login_server(""),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_retry_policy(RetryPolicy::default().initial_backoff(Duration::from_millis(1)));
+        session.authenticate("alice", "secret").await.unwrap();
+        assert!(session.send_message(message()).await.is_ok());
+        drop(session);
+
+        // The plaintext LOGIN that worked before is used again on the new
+        // connection, even though plaintext authentication was not opted in.
+        let received = server.await.unwrap();
+        assert_eq!(received[0].last().map(String::as_str), Some("MAIL FROM: <alice@example.com>"));
+        assert_eq!(received[1][1..5], ["AUTH LOGIN", "YWxpY2U=", "c2VjcmV0", "MAIL FROM: <alice@example.com>"]);
+        assert_eq!(received[1].last().map(String::as_str), Some("."));
+    }
+
+    #[tokio::test]
+    async fn test_no_resend_after_data_termination() {
+        let (address, server) = redial_server("220 mx.example.com ESMTP\r\n", vec![login_server(".")]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_retry_policy(RetryPolicy::default().initial_backoff(Duration::from_millis(1)));
+        let result = session.send_message(message()).await;
+        drop(session);
+
+        assert_eq!(result.unwrap_err(), Error::ClosedConnection("Read".to_string()));
+        assert_eq!(server.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_non_ascii_text_without_8bitmime() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250 mx.example.com\r\n"),
+            ("MAIL FROM:", "250 2.1.0 OK\r\n"),
+            ("RCPT TO:", "250 2.1.5 OK\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+            (".", "250 2.0.0 Queued\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mut message = message();
+        message.body = "Grüße".to_string();
+        session.send_message(message).await.unwrap();
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received[1], "MAIL FROM: <alice@example.com>");
+        assert!(received.iter().all(|line| line.is_ascii()));
+        assert!(received.contains(&"Content-Transfer-Encoding: quoted-printable".to_string()));
+        assert!(received.contains(&"Gr=C3=BC=C3=9Fe".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_binarymime_content_is_not_normalized() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250-CHUNKING\r\n250 BINARYMIME\r\n"),
+            ("BDAT", "250 2.0.0 OK\r\n"),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_chunk_size(4);
+        session.send_message_bdat(b"a\nb\rc\r\n".chunks(3), true).await.unwrap();
+        session.send_message_bdat(b"a\nb\rc".chunks(3), false).await.unwrap();
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received[1..], ["BDAT 4", "a\nb\r", "BDAT 3 LAST", "c\r\n", "BDAT 4", "a\r\nb", "BDAT 4", "\r\nc\r", "BDAT 1 LAST", "\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_envelope_is_sent_in_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Takes whatever arrives until the client goes quiet as one batch and
+        // answers it, so a client that writes more before reading replies
+        // shows up as a larger batch.
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+
+            writer.write_all(b"220 mx.example.com ESMTP\r\n").await.unwrap();
+            lines.next_line().await.unwrap();
+            writer.write_all(b"250-mx.example.com\r\n250 PIPELINING\r\n").await.unwrap();
+
+            let mut batches = Vec::new();
+            loop {
+                let mut batch = Vec::new();
+                while let Ok(Ok(Some(line))) = timeout(Duration::from_millis(200), lines.next_line()).await {
+                    batch.push(line);
+                }
+                batches.push(batch.len());
+
+                let replies: String = batch.iter()
+                    .map(|line| if line == "DATA" { "354 Go ahead\r\n" } else { "250 OK\r\n" })
+                    .collect();
+                writer.write_all(replies.as_bytes()).await.unwrap();
+                if batch.last().is_some_and(|line| line == "DATA") {
+                    break;
+                }
+            }
+
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if line == "." {
+                    writer.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+                    break;
+                }
+            }
+            batches
+        });
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        let mut message = message();
+        message.to = (0..150).map(|index| format!("user{}@example.com", index)).collect();
+        session.send_message(message).await.unwrap();
+        drop(session);
+
+        // MAIL, 150 RCPT and DATA.
+        assert_eq!(server.await.unwrap(), vec![PIPELINING_BATCH_SIZE, PIPELINING_BATCH_SIZE, 24]);
+    }
+
+    fn tls_config(pki: &TestPki) -> TlsConfig {
+        TlsConfig::default().add_root_certificate_file(pki.get_ca_file())
+    }
+
+    #[tokio::test]
+    async fn test_implicit_tls_handshake_comes_before_the_greeting() {
+        let pki = TestPki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = pki.acceptor();
+
+        // Nothing is sent in the clear: the greeting only goes out over TLS.
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            serve(stream, "220 mx.example.com ESMTP\r\n", &mut |command: &str| {
+                command.starts_with("EHLO").then(|| "250 mx.example.com\r\n".to_string())
+            }).await
+        });
+
+        let session = SmtpSession::connect(&format!("localhost:{}", port), ConnectionSecurity::Implicit, tls_config(&pki)).await.unwrap();
+        assert!(session.is_encrypted());
+        drop(session);
+
+        assert_eq!(server.await.unwrap(), vec!["EHLO localhost"]);
+    }
+
+    #[tokio::test]
+    async fn test_implicit_tls_rejects_untrusted_certificate() {
+        let pki = TestPki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = pki.acceptor();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(acceptor.accept(stream).await.is_err());
+        });
+
+        let result = SmtpSession::connect(&format!("localhost:{}", port), ConnectionSecurity::Implicit, TlsConfig::default()).await;
+        assert!(result.is_err());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_starttls_required_upgrades_the_connection() {
+        let pki = TestPki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = pki.acceptor();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+
+            stream.write_all(b"220 mx.example.com ESMTP\r\n").await.unwrap();
+            stream.read_line(&mut line).await.unwrap();
+            stream.write_all(b"250-mx.example.com\r\n250 STARTTLS\r\n").await.unwrap();
+            stream.read_line(&mut line).await.unwrap();
+            stream.write_all(b"220 2.0.0 Ready to start TLS\r\n").await.unwrap();
+
+            let stream = acceptor.accept(stream.into_inner()).await.unwrap();
+            let received = serve(stream, "", &mut |command: &str| {
+                command.starts_with("EHLO").then(|| "250 mx.example.com\r\n".to_string())
+            }).await;
+            (line, received)
+        });
+
+        let session = SmtpSession::connect(&format!("localhost:{}", port), ConnectionSecurity::StartTlsRequired, tls_config(&pki)).await.unwrap();
+        assert!(session.is_encrypted());
+        drop(session);
+
+        let (plain, encrypted) = server.await.unwrap();
+        assert_eq!(plain, "EHLO localhost\r\nSTARTTLS\r\n");
+        assert_eq!(encrypted, vec!["EHLO localhost"]);
+    }
+
+    #[tokio::test]
+    async fn test_starttls_required_fails_without_starttls() {
+        let (address, server) = scripted_server("220 mx.example.com ESMTP\r\n", vec![
+            ("EHLO", "250-mx.example.com\r\n250 8BITMIME\r\n"),
+        ]).await;
+
+        let result = SmtpSession::connect(&address, ConnectionSecurity::StartTlsRequired, TlsConfig::default()).await;
+        assert_eq!(result.err(), Some(Error::TlsUpgrade("STARTTLS is not supported by the server".to_string())));
+
+        // Nothing beyond EHLO is sent in the clear.
+        assert_eq!(server.await.unwrap(), vec!["EHLO localhost"]);
+    }
+
+    #[tokio::test]
+    async fn test_resend_after_transient_reply_to_end_of_data() {
+        let (address, server) = redial_server("220 mx.example.com ESMTP\r\n", vec![
+            login_server_with(".", Some("451 4.3.0 Temporary failure, try again\r\n")),
+            login_server(""),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_retry_policy(RetryPolicy::default().initial_backoff(Duration::from_millis(1)));
+        assert!(session.send_message(message()).await.is_ok());
+        drop(session);
+
+        // The 451 proves the first copy was not accepted.
+        let received = server.await.unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].last().map(String::as_str), Some("."));
+    }
+
+    #[tokio::test]
+    async fn test_resend_after_connection_dropped_mid_reply() {
+        let (address, server) = redial_server("220 mx.example.com ESMTP\r\n", vec![
+            login_server_with("MAIL FROM:", Some("250-2.1.0 Sender\r\n")),
+            login_server(""),
+        ]).await;
+
+        let mut session = SmtpSession::connect(&address, ConnectionSecurity::Plain, TlsConfig::default()).await.unwrap();
+        session.set_retry_policy(RetryPolicy::default().initial_backoff(Duration::from_millis(1)));
+        assert!(session.send_message(message()).await.is_ok());
+        drop(session);
+
+        let received = server.await.unwrap();
+        assert_eq!(received[0].last().map(String::as_str), Some("MAIL FROM: <alice@example.com>"));
+        assert_eq!(received[1].last().map(String::as_str), Some("."));
+    }
+}
@@ -1,11 +1,101 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, FixedOffset, Local};
 use error_handler::Error;
 
+use crate::mime::{encode_address_list, encode_parameter, encode_unstructured, format_headers, MimePart};
+
+/// Where the content of an attachment comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttachmentSource {
+    /// A file, read when the message is built.
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl From<PathBuf> for AttachmentSource {
+    fn from(path: PathBuf) -> Self {
+        AttachmentSource::Path(path)
+    }
+}
+
+impl From<&Path> for AttachmentSource {
+    fn from(path: &Path) -> Self {
+        AttachmentSource::Path(path.to_path_buf())
+    }
+}
+
+impl From<Vec<u8>> for AttachmentSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        AttachmentSource::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for AttachmentSource {
+    fn from(bytes: &[u8]) -> Self {
+        AttachmentSource::Bytes(bytes.to_vec())
+    }
+}
+
+/// A file sent along with the message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    fn to_mime_part(&self) -> MimePart {
+        MimePart::binary(vec![
+            ("Content-Type".to_string(), self.content_type.clone()),
+            ("Content-Disposition".to_string(), format!("attachment; {}", encode_parameter("filename", &self.filename))),
+        ], &self.data)
+    }
+}
+
+/// An image shown inside the HTML body, referenced there as `cid:<content_id>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InlineImage {
+    pub content_id: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl InlineImage {
+    fn to_mime_part(&self) -> MimePart {
+        MimePart::binary(vec![
+            ("Content-Type".to_string(), self.content_type.clone()),
+            ("Content-ID".to_string(), format!("<{}>", self.content_id)),
+            ("Content-Disposition".to_string(), "inline".to_string()),
+        ], &self.data)
+    }
+}
+
+/// Where a message takes the time for its Date header from.
+pub type Clock = fn() -> DateTime<FixedOffset>;
+
+/// The local time of the machine, the default `Clock`.
+pub fn system_clock() -> DateTime<FixedOffset> {
+    Local::now().fixed_offset()
+}
+
 #[derive(Debug)]
 pub struct SmtpMessage {
     pub from: String,
     pub to: Vec<String>,
+    pub cc: Vec<String>,
+    /// Recipients that get the message without being listed in it.
+    pub bcc: Vec<String>,
+    pub reply_to: Option<String>,
     pub subject: String,
+    pub date: DateTime<FixedOffset>,
+    /// The Message-ID header without its angle brackets.
+    pub message_id: String,
     pub body: String,
+    pub html_body: Option<String>,
+    pub inline_images: Vec<InlineImage>,
+    pub attachments: Vec<Attachment>,
 }
 
 impl SmtpMessage {
@@ -14,21 +104,191 @@ impl SmtpMessage {
         SmtpMessageBuilder::default()
     }
 
+    /// The message in Internet Message Format, every part encoded to 7bit.
     pub fn to_imf(&self) -> String {
+        // Without binary content only the header fields can hold non-ASCII,
+        // and they are built from strings.
+        String::from_utf8(self.render(&self.mime_body(), false)).unwrap()
+    }
+
+    /// The message for a server that accepts BINARYMIME (RFC 3030), with
+    /// 8-bit text and attachments as they are, or `None` when every part is
+    /// 7bit anyway and `to_imf` should be sent.
+    pub(crate) fn to_imf_binary(&self) -> Option<Vec<u8>> {
+        let mime_body = self.mime_body();
+        (!mime_body.is_7bit()).then(|| self.render(&mime_body, true))
+    }
+
+    /// Whether an address of the sender or of a recipient is not ASCII, so
+    /// the message can only go to a server that supports SMTPUTF8 (RFC 6531).
+    /// Display names don't count, as they are sent as encoded-words.
+    pub fn requires_smtputf8(&self) -> bool {
+        let reply_to = self.reply_to.as_deref().map(address_spec);
+
+        self.get_recipients().into_iter()
+            .chain(Some(self.get_sender()))
+            .chain(reply_to)
+            .any(|address| !address.is_ascii())
+    }
+
+    fn render(&self, mime_body: &MimePart, binary: bool) -> Vec<u8> {
         let mut imf_message = String::new();
         
-        imf_message.push_str(&format!("From: {}\r\n", self.from));
+        imf_message.push_str(&format!("From: {}\r\n", encode_address_list("From", std::slice::from_ref(&self.from))));
         
-        imf_message.push_str(&format!("To: {}\r\n", self.to.join(", ") ));
+        imf_message.push_str(&format!("To: {}\r\n", encode_address_list("To", &self.to)));
 
-        imf_message.push_str(&format!("Subject: {}\r\n", self.subject));
-        
+        if !self.cc.is_empty() {
+            imf_message.push_str(&format!("Cc: {}\r\n", encode_address_list("Cc", &self.cc)));
+        }
+
+        if let Some(reply_to) = &self.reply_to {
+            imf_message.push_str(&format!("Reply-To: {}\r\n", encode_address_list("Reply-To", std::slice::from_ref(reply_to))));
+        }
+
+        imf_message.push_str(&format!("Subject: {}\r\n", encode_unstructured("Subject", &self.subject)));
+
+        imf_message.push_str(&format!("Date: {}\r\n", self.date.format("%a, %d %b %Y %H:%M:%S %z")));
+
+        imf_message.push_str(&format!("Message-ID: <{}>\r\n", self.message_id));
+
+        let (headers, body) = mime_body.render(binary);
+
+        imf_message.push_str("MIME-Version: 1.0\r\n");
+        imf_message.push_str(&format_headers(&headers));
         imf_message.push_str("\r\n");
-        
-        imf_message.push_str(&self.body);
-        
-        imf_message
+
+        [imf_message.into_bytes(), body].concat()
+    }
+
+    /// The address of the sender, without a display name.
+    pub fn get_sender(&self) -> &str {
+        address_spec(&self.from)
     }
+
+    /// Every address the message is delivered to: To, Cc and Bcc, each
+    /// once, in that order, without display names.
+    pub fn get_recipients(&self) -> Vec<&str> {
+        let mut recipients: Vec<&str> = Vec::new();
+
+        for mailbox in self.to.iter().chain(self.cc.iter()).chain(self.bcc.iter()) {
+            let address = address_spec(mailbox);
+            if !recipients.contains(&address) {
+                recipients.push(address);
+            }
+        }
+
+        recipients
+    }
+
+    /// The MIME structure of the body.
+    fn mime_body(&self) -> MimePart {
+        let text = match &self.html_body {
+            Some(html) => {
+                let mut html = MimePart::text("html", html);
+                if !self.inline_images.is_empty() {
+                    let mut parts = vec![html];
+                    parts.extend(self.inline_images.iter().map(InlineImage::to_mime_part));
+                    html = MimePart::Multipart { subtype: "related", parts };
+                }
+
+                MimePart::Multipart {
+                    subtype: "alternative",
+                    // Least preferred first (RFC 2046 section 5.1.4).
+                    parts: vec![MimePart::text("plain", &self.body), html],
+                }
+            },
+            None => MimePart::text("plain", &self.body),
+        };
+
+        if self.attachments.is_empty() {
+            return text;
+        }
+
+        let mut parts = vec![text];
+        parts.extend(self.attachments.iter().map(Attachment::to_mime_part));
+        MimePart::Multipart { subtype: "mixed", parts }
+    }
+}
+
+/// The address in a `Display Name <address>` mailbox, or the mailbox
+/// itself when it is a bare address.
+fn address_spec(mailbox: &str) -> &str {
+    let mailbox = mailbox.trim();
+
+    mailbox.strip_suffix('>')
+        .and_then(|rest| rest.rsplit_once('<'))
+        .map(|(_, address)| address)
+        .unwrap_or(mailbox)
+}
+
+/// Collects the Content-IDs referenced by `cid:` URLs in `html`, decoding
+/// the %hh escapes RFC 2392 allows in them.
+fn referenced_content_ids(html: &str) -> Vec<String> {
+    let lowercase = html.to_ascii_lowercase();
+    let mut content_ids = Vec::new();
+
+    for (start, _) in lowercase.match_indices("cid:") {
+        // Skip words that merely end in "cid", as in "Placid:".
+        if start > 0 && lowercase.as_bytes()[start - 1].is_ascii_alphanumeric() {
+            continue;
+        }
+
+        let url = &html[start + 4..];
+        let end = url.find(|c: char| c.is_whitespace() || "\"'()<>".contains(c)).unwrap_or(url.len());
+
+        let content_id = percent_decode(&url[..end]);
+        if !content_id.is_empty() && !content_ids.contains(&content_id) {
+            content_ids.push(content_id);
+        }
+    }
+
+    content_ids
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        match (bytes[i], escaped) {
+            (b'%', Some(value)) => {
+                decoded.push(value);
+                i += 3;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A new globally unique Message-ID for a message sent by `from`: the
+/// time and a random number, at the domain of the sender (RFC 5322
+/// section 3.6.4).
+fn generate_message_id(from: &str, date: &DateTime<FixedOffset>) -> String {
+    let domain = from.rsplit_once('@')
+        .map(|(_, domain)| domain.trim_end_matches(|c: char| c == '>' || c.is_whitespace()))
+        .filter(|domain| !domain.is_empty())
+        .unwrap_or("localhost");
+
+    format!("{}.{:016x}@{}", date.timestamp_micros(), rand::random::<u64>(), domain)
+}
+
+/// Converts HTML to readable plain text for the text alternative, with
+/// links, lists and tables laid out for a 78 column display.
+fn html_to_text(html: &str) -> String {
+    html2text::from_read(html.as_bytes(), 78)
+        .trim_end()
+        .replace('\n', "\r\n")
 }
 
 
@@ -37,11 +297,21 @@ impl SmtpMessage {
 pub struct SmtpMessageBuilder {
     from: Option<String>,
     to: Vec<String>,
+    cc: Vec<String>,
+    bcc: Vec<String>,
+    reply_to: Option<String>,
     subject: Option<String>,
     body: Option<String>,
+    html_body: Option<String>,
+    inline_images: Vec<InlineImage>,
+    attachments: Vec<(AttachmentSource, String, Option<String>)>,
+    clock: Option<Clock>,
 }
 
 impl SmtpMessageBuilder {
+    // Kept as an inherent method so existing callers don't need `Default`
+    // in scope.
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Self {
         Default::default()
     }
@@ -56,6 +326,23 @@ impl SmtpMessageBuilder {
         self
     }
 
+    pub fn cc(mut self, cc: &str) -> Self {
+        self.cc.push(cc.to_string());
+        self
+    }
+
+    /// Adds a blind copy recipient. It is only given to the server as a
+    /// RCPT TO and never written in the message headers.
+    pub fn bcc(mut self, bcc: &str) -> Self {
+        self.bcc.push(bcc.to_string());
+        self
+    }
+
+    pub fn reply_to(mut self, reply_to: &str) -> Self {
+        self.reply_to = Some(reply_to.to_string());
+        self
+    }
+
     pub fn subject(mut self, subject: &str) -> Self {
         self.subject = Some(subject.to_string());
         self
@@ -66,6 +353,38 @@ impl SmtpMessageBuilder {
         self
     }
 
+    /// Sends `html` along with a plain text alternative: the `body` if one
+    /// is set, otherwise text converted from the HTML.
+    pub fn html_body(mut self, html: &str) -> Self {
+        self.html_body = Some(html.to_string());
+        self
+    }
+
+    /// Adds an image the HTML body shows with a `cid:<content_id>` URL.
+    pub fn inline_image(mut self, data: &[u8], content_id: &str, content_type: &str) -> Self {
+        let content_id = content_id.trim_start_matches('<').trim_end_matches('>');
+        self.inline_images.push(InlineImage {
+            content_id: content_id.to_string(),
+            content_type: content_type.to_string(),
+            data: data.to_vec(),
+        });
+        self
+    }
+
+    /// Attaches a file or in-memory content under `filename`. Without a
+    /// `content_type` the type is guessed from the filename extension.
+    pub fn attachment<S: Into<AttachmentSource>>(mut self, source: S, filename: &str, content_type: Option<&str>) -> Self {
+        self.attachments.push((source.into(), filename.to_string(), content_type.map(str::to_string)));
+        self
+    }
+
+    /// Replaces the clock the Date header is taken from, `system_clock` by
+    /// default.
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
     pub fn build(self) -> Result<SmtpMessage, Error> {
         if self.from.is_none() {
             return Err(Error::MessageBuild("Missing 'from' field".to_string()));
@@ -79,15 +398,56 @@ impl SmtpMessageBuilder {
             return Err(Error::MessageBuild("Missing 'subject' field".to_string()));
         }
 
-        if self.body.is_none() {
-            return Err(Error::MessageBuild("Missing 'body' field".to_string()));
+        let body = match (self.body, &self.html_body) {
+            (Some(body), _) => body,
+            (None, Some(html)) => html_to_text(html),
+            (None, None) => return Err(Error::MessageBuild("Missing 'body' field".to_string())),
+        };
+
+        match &self.html_body {
+            Some(html) => {
+                for content_id in referenced_content_ids(html) {
+                    if !self.inline_images.iter().any(|image| image.content_id == content_id) {
+                        return Err(Error::MessageBuild(format!("No inline image for 'cid:{}'", content_id)));
+                    }
+                }
+            },
+            None if !self.inline_images.is_empty() => {
+                return Err(Error::MessageBuild("Inline images need an HTML body".to_string()));
+            },
+            None => {},
         }
 
+        let mut attachments = Vec::new();
+        for (source, filename, content_type) in self.attachments {
+            let data = match source {
+                AttachmentSource::Path(path) => std::fs::read(path)?,
+                AttachmentSource::Bytes(bytes) => bytes,
+            };
+            let content_type = content_type.unwrap_or_else(|| {
+                mime_guess::from_path(&filename).first_or_octet_stream().to_string()
+            });
+
+            attachments.push(Attachment { filename, content_type, data });
+        }
+
+        let from = self.from.unwrap();
+        let date = self.clock.unwrap_or(system_clock)();
+        let message_id = generate_message_id(&from, &date);
+
         Ok(SmtpMessage {
-            from: self.from.unwrap(),
+            from,
             to: self.to,
+            cc: self.cc,
+            bcc: self.bcc,
+            reply_to: self.reply_to,
             subject: self.subject.unwrap(),
-            body: self.body.unwrap(),
+            date,
+            message_id,
+            body,
+            html_body: self.html_body,
+            inline_images: self.inline_images,
+            attachments,
         })
     }
 }
@@ -99,6 +459,11 @@ impl SmtpMessageBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mime::parse_headers;
+
+    fn clock() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2024-03-05T09:07:02+01:00").unwrap()
+    }
 
     #[test]
     fn test_smtp_to_imf() {
@@ -107,14 +472,51 @@ mod tests {
             .to("emilydoe@gmail.com")
             .subject("Hello")
             .body("Hello, Emily!")
+            .clock(clock)
             .build().unwrap();
 
         assert_eq!(message.to_imf(),
-                concat!("From: johndoe@gmail.com\r\n",
-                        "To: emilydoe@gmail.com\r\n",
-                        "Subject: Hello\r\n",
-                        "\r\n",
-                        "Hello, Emily!"));
+                format!(concat!("From: johndoe@gmail.com\r\n",
+                                "To: emilydoe@gmail.com\r\n",
+                                "Subject: Hello\r\n",
+                                "Date: Tue, 05 Mar 2024 09:07:02 +0100\r\n",
+                                "Message-ID: <{}>\r\n",
+                                "MIME-Version: 1.0\r\n",
+                                "Content-Type: text/plain; charset=utf-8\r\n",
+                                "Content-Transfer-Encoding: 7bit\r\n",
+                                "\r\n",
+                                "Hello, Emily!"), message.message_id));
+    }
+
+    #[test]
+    fn test_message_id() {
+        let builder = || SmtpMessage::builder()
+            .from("John Doe <johndoe@mail.example.com>")
+            .to("emilydoe@gmail.com")
+            .subject("Hello")
+            .body("Hello, Emily!")
+            .clock(clock);
+
+        let first = builder().build().unwrap();
+        let second = builder().build().unwrap();
+
+        assert!(first.message_id.ends_with("@mail.example.com"));
+        assert!(!first.message_id.contains(|c: char| c.is_whitespace() || "<>\"".contains(c)));
+        assert_ne!(first.message_id, second.message_id);
+        assert!(first.to_imf().contains(&format!("\r\nMessage-ID: <{}>\r\n", first.message_id)));
+    }
+
+    #[test]
+    fn test_date_uses_system_clock_by_default() {
+        let before = Local::now();
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Hello")
+            .body("Hello, Emily!")
+            .build().unwrap();
+
+        assert!(message.date.timestamp() >= before.timestamp() && message.date <= Local::now());
     }
 
     #[test]
@@ -125,14 +527,262 @@ mod tests {
             .to("alicedoe@gmail.com")
             .subject("Good evening")
             .body("Good evening, Emily and Alice!")
+            .clock(clock)
             .build().unwrap();
 
-        assert_eq!(message.to_imf(),
+        assert!(message.to_imf().starts_with(
                 concat!("From: johndoe@gmail.com\r\n",
                         "To: emilydoe@gmail.com, alicedoe@gmail.com\r\n",
-                        "Subject: Good evening\r\n",
-                        "\r\n",
-                        "Good evening, Emily and Alice!"));
+                        "Subject: Good evening\r\n")));
+        assert!(message.to_imf().ends_with("\r\n\r\nGood evening, Emily and Alice!"));
+    }
+
+    #[test]
+    fn test_smtp_to_imf_cc_and_reply_to() {
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .cc("alicedoe@gmail.com")
+            .cc("bobdoe@gmail.com")
+            .reply_to("support@gmail.com")
+            .subject("Hello")
+            .body("Hello, everyone!")
+            .build().unwrap();
+
+        assert!(message.to_imf().starts_with(
+                concat!("From: johndoe@gmail.com\r\n",
+                        "To: emilydoe@gmail.com\r\n",
+                        "Cc: alicedoe@gmail.com, bobdoe@gmail.com\r\n",
+                        "Reply-To: support@gmail.com\r\n",
+                        "Subject: Hello\r\n")));
+    }
+
+    #[test]
+    fn test_bcc_is_not_in_imf() {
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .cc("alicedoe@gmail.com")
+            .bcc("secret.auditor@gmail.com")
+            .bcc("Hidden.Boss@corp.example")
+            .subject("Quarterly numbers")
+            .body("Numbers attached.")
+            .html_body("<p>Numbers attached.</p>")
+            .attachment(vec![1, 2, 3], "numbers.xlsx", None)
+            .build().unwrap();
+
+        let imf = message.to_imf();
+        for bcc in message.bcc.iter() {
+            assert!(!imf.to_lowercase().contains(&bcc.to_lowercase()));
+            assert!(!imf.to_lowercase().contains(bcc.split('@').next().unwrap().to_lowercase().as_str()));
+        }
+        assert!(!imf.lines().any(|line| line.to_lowercase().starts_with("bcc:")));
+
+        assert_eq!(message.get_recipients(), vec![
+            "emilydoe@gmail.com", "alicedoe@gmail.com", "secret.auditor@gmail.com", "Hidden.Boss@corp.example",
+        ]);
+    }
+
+    #[test]
+    fn test_recipients_are_deduplicated() {
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .cc("emilydoe@gmail.com")
+            .bcc("alicedoe@gmail.com")
+            .bcc("emilydoe@gmail.com")
+            .subject("Hello")
+            .body("Hello!")
+            .build().unwrap();
+
+        assert_eq!(message.get_recipients(), vec!["emilydoe@gmail.com", "alicedoe@gmail.com"]);
+    }
+
+    #[test]
+    fn test_non_ascii_headers_are_encoded() {
+        let subject = "Запрошення на зустріч щодо бюджету наступного року для всіх відділів";
+        let message = SmtpMessage::builder()
+            .from("Олена Коваль <olena@example.com>")
+            .to("Тарас <taras@example.com>")
+            .to("emilydoe@gmail.com")
+            .subject(subject)
+            .body("Привіт!")
+            .build().unwrap();
+
+        let imf = message.to_imf();
+        let (head, _) = imf.split_once("\r\n\r\n").unwrap();
+        assert!(head.is_ascii());
+        assert!(head.split("\r\n").all(|line| line.len() <= 78));
+
+        let headers = parse_headers(&imf).unwrap();
+        let header = |name: &str| headers.iter().find(|(field, _)| field == name).map(|(_, value)| value.as_str());
+        assert_eq!(header("Subject"), Some(subject));
+        assert_eq!(header("From"), Some("Олена Коваль <olena@example.com>"));
+        assert_eq!(header("To"), Some("Тарас <taras@example.com>, emilydoe@gmail.com"));
+
+        assert_eq!(message.get_sender(), "olena@example.com");
+        assert_eq!(message.get_recipients(), vec!["taras@example.com", "emilydoe@gmail.com"]);
+    }
+
+    #[test]
+    fn test_smtp_to_imf_with_attachments() {
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Report")
+            .body("See attached.")
+            .attachment(vec![0x25, 0x50, 0x44, 0x46], "report.pdf", None)
+            .attachment(&b"a,b\n"[..], "Übersicht.csv", Some("text/csv"))
+            .build().unwrap();
+
+        let imf = message.to_imf();
+        let (head, body) = imf.split_once("\r\n\r\n").unwrap();
+        let boundary = head.split("boundary=\"").nth(1).unwrap().trim_end_matches('"');
+
+        assert!(head.starts_with("From: johndoe@gmail.com\r\nTo: emilydoe@gmail.com\r\nSubject: Report\r\n"));
+        assert!(head.contains("\r\nMIME-Version: 1.0\r\n"));
+        assert!(head.contains("Content-Type: multipart/mixed; boundary="));
+
+        let parts: Vec<&str> = body.split(&format!("--{}", boundary)).collect();
+        assert_eq!(parts.len(), 5);
+        assert_eq!(parts[1], "\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 7bit\r\n\r\nSee attached.\r\n");
+        assert_eq!(parts[2], concat!("\r\nContent-Type: application/pdf\r\n",
+                                     "Content-Disposition: attachment; filename=\"report.pdf\"\r\n",
+                                     "Content-Transfer-Encoding: base64\r\n\r\nJVBERg==\r\n"));
+        assert_eq!(parts[3], concat!("\r\nContent-Type: text/csv\r\n",
+                                     "Content-Disposition: attachment; filename*=utf-8''%C3%9Cbersicht.csv\r\n",
+                                     "Content-Transfer-Encoding: base64\r\n\r\nYSxiCg==\r\n"));
+        assert_eq!(parts[4], "--\r\n");
+    }
+
+    #[test]
+    fn test_html_body_with_generated_text() {
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Build failed")
+            .html_body("<h1>Build #42</h1><p>The build <b>failed</b>.</p><ul><li>lint</li><li>tests</li></ul>")
+            .build().unwrap();
+
+        assert!(message.body.contains("Build #42"));
+        assert!(message.body.contains("The build failed."));
+        assert!(message.body.contains("lint") && message.body.contains("tests"));
+        assert!(!message.body.contains('<'));
+
+        let imf = message.to_imf();
+        let (head, body) = imf.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("MIME-Version: 1.0\r\nContent-Type: multipart/alternative; boundary="));
+
+        let plain = body.find("Content-Type: text/plain; charset=utf-8").unwrap();
+        let html = body.find("Content-Type: text/html; charset=utf-8").unwrap();
+        assert!(plain < html);
+        assert!(body.contains("<h1>Build #42</h1>"));
+    }
+
+    #[test]
+    fn test_html_body_with_attachment() {
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Invoice")
+            .body("Invoice attached.")
+            .html_body("<p>Invoice attached.</p>")
+            .attachment(vec![1, 2, 3], "invoice.pdf", None)
+            .build().unwrap();
+
+        assert_eq!(message.body, "Invoice attached.");
+
+        let imf = message.to_imf();
+        let mixed = imf.find("multipart/mixed").unwrap();
+        let alternative = imf.find("multipart/alternative").unwrap();
+        let attachment = imf.find("filename=\"invoice.pdf\"").unwrap();
+        assert!(mixed < alternative && alternative < attachment);
+    }
+
+    #[test]
+    fn test_inline_images() {
+        let message = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Newsletter")
+            .body("Our logo")
+            .html_body("<p>Placid: <img src=\"cid:logo@example.com\"> <img src='CID:chart%201'></p>")
+            .inline_image(&[0x89, b'P', b'N', b'G'], "<logo@example.com>", "image/png")
+            .inline_image(&[0xFF, 0xD8], "chart 1", "image/jpeg")
+            .build().unwrap();
+
+        assert_eq!(message.inline_images[0].content_id, "logo@example.com");
+
+        let imf = message.to_imf();
+        let alternative = imf.find("multipart/alternative").unwrap();
+        let related = imf.find("Content-Type: multipart/related; boundary=").unwrap();
+        let html = imf.find("Content-Type: text/html").unwrap();
+        let logo = imf.find("Content-ID: <logo@example.com>").unwrap();
+        assert!(alternative < related && related < html && html < logo);
+        assert!(imf.contains("; type=\"text/html\""));
+        assert!(imf.contains("Content-Type: image/png\r\nContent-ID: <logo@example.com>\r\nContent-Disposition: inline\r\nContent-Transfer-Encoding: base64\r\n"));
+        assert!(imf.contains("Content-ID: <chart 1>"));
+    }
+
+    #[test]
+    fn test_inline_image_references_are_checked() {
+        let builder = || SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Newsletter");
+
+        let missing = builder()
+            .html_body("<img src=\"cid:logo\"><img src=\"cid:banner\">")
+            .inline_image(&[1], "logo", "image/png")
+            .build();
+        assert_eq!(missing.err(), Some(Error::MessageBuild("No inline image for 'cid:banner'".to_string())));
+
+        let without_html = builder()
+            .body("No HTML")
+            .inline_image(&[1], "logo", "image/png")
+            .build();
+        assert!(without_html.is_err());
+    }
+
+    #[test]
+    fn test_attachment_from_file() {
+        let path = std::env::temp_dir().join(format!("smtp_session_attachment_{}.png", std::process::id()));
+        std::fs::write(&path, [0x89, b'P', b'N', b'G']).unwrap();
+
+        let builder = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Picture")
+            .body("");
+        let message = builder.attachment(path.as_path(), "picture.png", None).build().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(message.attachments, vec![Attachment {
+            filename: "picture.png".to_string(),
+            content_type: "image/png".to_string(),
+            data: vec![0x89, b'P', b'N', b'G'],
+        }]);
+
+        let missing = SmtpMessage::builder()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Picture")
+            .body("")
+            .attachment(path.as_path(), "picture.png", None)
+            .build();
+        assert!(matches!(missing, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_builder_default() {
+        let message = SmtpMessageBuilder::default()
+            .from("johndoe@gmail.com")
+            .to("emilydoe@gmail.com")
+            .subject("Hello")
+            .body("Hello, Emily!")
+            .build().unwrap();
+
+        assert_eq!(message.to, vec!["emilydoe@gmail.com"]);
     }
 
     #[test]
@@ -0,0 +1,153 @@
+use crate::base64;
+
+/// Longest line of base64 content allowed by RFC 2045 section 6.8.
+const BASE64_LINE_LENGTH: usize = 76;
+
+/// Longest encoded line of quoted-printable content, including the "=" of a
+/// soft line break (RFC 2045 section 6.7).
+const QUOTED_PRINTABLE_LINE_LENGTH: usize = 76;
+
+/// Longest value put in one RFC 2231 continuation section, so header lines
+/// stay well under 78 characters.
+const PARAMETER_SECTION_LENGTH: usize = 60;
+
+/// Base64 for a MIME body, in lines of at most 76 characters.
+pub fn base64_lines(data: &[u8]) -> String {
+    let encoded = base64::encode_bytes(data);
+
+    encoded.as_bytes()
+        .chunks(BASE64_LINE_LENGTH)
+        .map(|line| std::str::from_utf8(line).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Quoted-printable for a text body. Line breaks stay hard CRLF breaks and
+/// longer lines are split with soft "=" breaks; whitespace at the end of a
+/// line is encoded so transports that strip it do not change the text.
+pub fn quoted_printable(text: &str) -> String {
+    let mut encoded = String::new();
+
+    for (index, line) in text.split('\n').enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line).as_bytes();
+        if index > 0 {
+            encoded += "\r\n";
+        }
+
+        let mut column = 0;
+        for (position, &byte) in line.iter().enumerate() {
+            let is_last = position + 1 == line.len();
+            let token = match byte {
+                b' ' | b'\t' if !is_last => (byte as char).to_string(),
+                b'!'..=b'<' | b'>'..=b'~' => (byte as char).to_string(),
+                _ => format!("={:02X}", byte),
+            };
+
+            // Leaves room for the "=" of a soft break unless the line ends here.
+            let limit = if is_last { QUOTED_PRINTABLE_LINE_LENGTH } else { QUOTED_PRINTABLE_LINE_LENGTH - 1 };
+            if column + token.len() > limit {
+                encoded += "=\r\n";
+                column = 0;
+            }
+            encoded += &token;
+            column += token.len();
+        }
+    }
+
+    encoded
+}
+
+/// Formats `name=value` for a header parameter. Printable ASCII goes in a
+/// quoted string; anything else uses the RFC 2231 extended form, split into
+/// continuation sections when long. Sections after the first are separated
+/// by ";" and a folding line break.
+pub fn encode_parameter(name: &str, value: &str) -> String {
+    if value.bytes().all(|byte| (0x20..0x7f).contains(&byte)) {
+        return format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""));
+    }
+
+    let mut sections = Vec::new();
+    let mut section = String::new();
+
+    for character in value.chars() {
+        let mut buffer = [0; 4];
+        let encoded: String = character.encode_utf8(&mut buffer)
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+                | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect();
+
+        // A character is never split, so each section stays valid UTF-8.
+        if !section.is_empty() && section.len() + encoded.len() > PARAMETER_SECTION_LENGTH {
+            sections.push(std::mem::take(&mut section));
+        }
+        section += &encoded;
+    }
+    sections.push(section);
+
+    if sections.len() == 1 {
+        return format!("{}*=utf-8''{}", name, sections[0]);
+    }
+
+    sections.iter()
+        .enumerate()
+        .map(|(index, section)| match index {
+            0 => format!("{}*0*=utf-8''{}", name, section),
+            _ => format!("{}*{}*={}", name, index, section),
+        })
+        .collect::<Vec<_>>()
+        .join(";\r\n ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_lines() {
+        let encoded = base64_lines(&[0xAB; 200]);
+        let lines: Vec<&str> = encoded.split("\r\n").collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[..3].iter().all(|line| line.len() == 76));
+        assert_eq!(base64::decode(&lines.concat()).unwrap(), vec![0xAB; 200]);
+        assert_eq!(base64_lines(b""), "");
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        assert_eq!(quoted_printable("Grüße = 5\r\nbye \n"), "Gr=C3=BC=C3=9Fe =3D 5\r\nbye=20\r\n");
+        assert_eq!(quoted_printable("plain text"), "plain text");
+
+        let encoded = quoted_printable(&"é".repeat(100));
+        let lines: Vec<&str> = encoded.split("\r\n").collect();
+        assert!(lines.iter().all(|line| line.len() <= 76));
+        assert!(lines[..lines.len() - 1].iter().all(|line| line.ends_with('=')));
+        assert_eq!(lines.concat().replace('=', "").len(), 400);
+    }
+
+    #[test]
+    fn test_ascii_parameter_is_quoted() {
+        assert_eq!(encode_parameter("filename", "report.pdf"), "filename=\"report.pdf\"");
+        assert_eq!(encode_parameter("filename", "say \"hi\".txt"), "filename=\"say \\\"hi\\\".txt\"");
+    }
+
+    #[test]
+    fn test_rfc_2231_parameter() {
+        assert_eq!(encode_parameter("filename", "€ rates.pdf"), "filename*=utf-8''%E2%82%AC%20rates.pdf");
+        assert_eq!(encode_parameter("filename", "tab\there"), "filename*=utf-8''tab%09here");
+    }
+
+    #[test]
+    fn test_rfc_2231_continuations() {
+        let encoded = encode_parameter("filename", &"ü".repeat(20));
+        let sections: Vec<&str> = encoded.split(";\r\n ").collect();
+
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0], format!("filename*0*=utf-8''{}", "%C3%BC".repeat(10)));
+        assert_eq!(sections[1], format!("filename*1*={}", "%C3%BC".repeat(10)));
+    }
+}
@@ -0,0 +1,414 @@
+use error_handler::Error;
+use regex::Regex;
+
+use crate::base64;
+
+/// Longest header line, without the CRLF (RFC 5322 section 2.1.1).
+const LINE_LENGTH: usize = 78;
+
+/// Longest encoded-word allowed by RFC 2047 section 2.
+const ENCODED_WORD_LENGTH: usize = 75;
+
+/// Length of "=?utf-8?B?" and "?=" around the encoded text.
+const ENCODED_WORD_OVERHEAD: usize = 12;
+
+/// Shortest first encoded-word worth starting on a partly filled line.
+const MIN_ENCODED_WORD_LENGTH: usize = ENCODED_WORD_OVERHEAD + 8;
+
+#[derive(Clone, Copy, PartialEq)]
+enum WordEncoding {
+    B,
+    Q,
+}
+
+impl WordEncoding {
+    /// The encoding that gives the shorter result for `text`.
+    fn for_text(text: &str) -> Self {
+        let q_length: usize = text.bytes().map(|byte| q_byte(byte).len()).sum();
+        let b_length = text.len().div_ceil(3) * 4;
+
+        if q_length <= b_length { WordEncoding::Q } else { WordEncoding::B }
+    }
+
+    fn encoded_length(&self, text: &str) -> usize {
+        match self {
+            WordEncoding::B => text.len().div_ceil(3) * 4,
+            WordEncoding::Q => text.bytes().map(|byte| q_byte(byte).len()).sum(),
+        }
+    }
+
+    fn encode_word(&self, text: &str) -> String {
+        match self {
+            WordEncoding::B => format!("=?utf-8?B?{}?=", base64::encode(text)),
+            WordEncoding::Q => format!("=?utf-8?Q?{}?=", text.bytes().map(q_byte).collect::<String>()),
+        }
+    }
+}
+
+/// Matches an RFC 2047 encoded-word, capturing its charset, encoding and
+/// encoded text.
+fn encoded_word_regex() -> Regex {
+    Regex::new(r"=\?([^?\s]+)\?([BbQq])\?([^?\s]*)\?=").unwrap()
+}
+
+/// Whether `text` must be encoded to survive `decode_header_value`: it is
+/// not ASCII, or it contains something a decoder would take for an
+/// encoded-word.
+fn needs_encoding(text: &str) -> bool {
+    !text.is_ascii() || encoded_word_regex().is_match(text)
+}
+
+/// One byte in the Q encoding. Only the characters RFC 2047 section 5 (3)
+/// allows in a phrase are left as they are, so the result fits anywhere.
+fn q_byte(byte: u8) -> String {
+    match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'*' | b'+' | b'-' | b'/' => (byte as char).to_string(),
+        b' ' => "_".to_string(),
+        _ => format!("={:02X}", byte),
+    }
+}
+
+/// Splits `text` into encoded-words, the first at most `first_length`
+/// characters long and the others at most 75. A character is never split
+/// between two words.
+fn encode_words(text: &str, first_length: usize) -> Vec<String> {
+    let encoding = WordEncoding::for_text(text);
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut max_length = first_length;
+
+    for (index, character) in text.char_indices() {
+        let end = index + character.len_utf8();
+        if index > start && ENCODED_WORD_OVERHEAD + encoding.encoded_length(&text[start..end]) > max_length {
+            words.push(encoding.encode_word(&text[start..index]));
+            start = index;
+            max_length = ENCODED_WORD_LENGTH;
+        }
+    }
+    words.push(encoding.encode_word(&text[start..]));
+
+    words
+}
+
+/// A header value being laid out in lines of at most 78 characters.
+struct FoldedValue {
+    m_value: String,
+    m_column: usize,
+}
+
+impl FoldedValue {
+    fn new(name: &str) -> Self {
+        Self {
+            m_value: String::new(),
+            // The value starts after "Name: ".
+            m_column: name.len() + 2,
+        }
+    }
+
+    /// Appends `token` after a space, or on a new line when it doesn't fit,
+    /// which may leave only the header name on the first line.
+    fn push(&mut self, token: &str) {
+        let separator = if self.m_value.is_empty() { "" } else { " " };
+
+        if self.m_column + separator.len() + token.len() > LINE_LENGTH {
+            self.m_value += "\r\n ";
+            self.m_column = 1;
+        } else {
+            self.m_value += separator;
+            self.m_column += separator.len();
+        }
+
+        self.m_value += token;
+        self.m_column += token.len();
+    }
+
+    /// Appends `text` as encoded-words, filling the current line first.
+    fn push_encoded(&mut self, text: &str) {
+        let separator = if self.m_value.is_empty() { 0 } else { 1 };
+        let room = LINE_LENGTH.saturating_sub(self.m_column + separator).min(ENCODED_WORD_LENGTH);
+        let first_length = if room >= MIN_ENCODED_WORD_LENGTH { room } else { ENCODED_WORD_LENGTH };
+
+        for word in encode_words(text, first_length) {
+            self.push(&word);
+        }
+    }
+}
+
+/// Encodes an unstructured header value such as the Subject, folded so no
+/// line of the `name` header exceeds 78 characters where it can be broken.
+/// Non-ASCII text, and text that would be mistaken for encoded-words,
+/// becomes RFC 2047 encoded-words, in B or Q encoding, whichever is
+/// shorter. Other text is folded at its spaces.
+pub fn encode_unstructured(name: &str, value: &str) -> String {
+    let mut folded = FoldedValue::new(name);
+
+    if needs_encoding(value) {
+        folded.push_encoded(value);
+    } else {
+        // Only a space before a word is a fold point; any other spaces stay
+        // at the end of the word before, so none is lost.
+        let mut start = 0;
+        for (index, _) in value.match_indices(' ') {
+            if value[index + 1..].starts_with(|character| character != ' ') {
+                folded.push(&value[start..index]);
+                start = index + 1;
+            }
+        }
+        folded.push(&value[start..]);
+    }
+
+    folded.m_value
+}
+
+/// Encodes a list of mailboxes, `address` or `Display Name <address>`,
+/// for the `name` header, folded between mailboxes. A display name that
+/// needs it becomes encoded-words; the address itself can't be encoded
+/// and is left as it is.
+pub fn encode_address_list(name: &str, mailboxes: &[String]) -> String {
+    let mut folded = FoldedValue::new(name);
+
+    for (index, mailbox) in mailboxes.iter().enumerate() {
+        let separator = if index + 1 < mailboxes.len() { "," } else { "" };
+        let mailbox = mailbox.trim();
+
+        match mailbox.rsplit_once('<').filter(|_| mailbox.ends_with('>')) {
+            Some((display_name, address)) if needs_encoding(display_name) => {
+                folded.push_encoded(display_name.trim().trim_matches('"'));
+                folded.push(&format!("<{}{}", address, separator));
+            },
+            _ => folded.push(&format!("{}{}", mailbox, separator)),
+        }
+    }
+
+    folded.m_value
+}
+
+/// Decodes the RFC 2047 encoded-words in a header value, unfolding it
+/// first. Whitespace between two encoded-words is dropped. Words in a
+/// charset other than UTF-8, US-ASCII or ISO-8859-1 are kept as they are.
+pub fn decode_header_value(value: &str) -> Result<String, Error> {
+    let value = value.replace("\r\n", "");
+    let re = encoded_word_regex();
+
+    let mut decoded = String::new();
+    let mut last_end = 0;
+    let mut after_encoded_word = false;
+
+    for captures in re.captures_iter(&value) {
+        let word = captures.get(0).unwrap();
+        let gap = &value[last_end..word.start()];
+
+        if !(after_encoded_word && gap.chars().all(char::is_whitespace)) {
+            decoded += gap;
+        }
+
+        decoded += &decode_word(word.as_str(), &captures[1], &captures[2], &captures[3])?;
+        last_end = word.end();
+        after_encoded_word = true;
+    }
+
+    decoded += &value[last_end..];
+    Ok(decoded)
+}
+
+fn decode_word(word: &str, charset: &str, encoding: &str, text: &str) -> Result<String, Error> {
+    let invalid = || Error::MessageBuild(format!("Invalid encoded-word: {}", word));
+
+    let bytes = match encoding {
+        "B" | "b" => base64::decode(text).map_err(|_| invalid())?,
+        _ => {
+            let mut bytes = Vec::with_capacity(text.len());
+            let mut rest = text.as_bytes();
+            while let Some((&byte, next)) = rest.split_first() {
+                rest = next;
+                match byte {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        // from_str_radix alone would also take a sign, as in "=+A".
+                        let hex = rest.get(..2)
+                            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                            .and_then(|hex| std::str::from_utf8(hex).ok())
+                            .ok_or_else(invalid)?;
+                        bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                        rest = &rest[2..];
+                    },
+                    _ => bytes.push(byte),
+                }
+            }
+            bytes
+        },
+    };
+
+    // RFC 2231 section 5 allows a language after the charset.
+    let charset = charset.split('*').next().unwrap_or_default().to_ascii_lowercase();
+    match charset.as_str() {
+        "utf-8" | "us-ascii" => String::from_utf8(bytes).map_err(|_| invalid()),
+        "iso-8859-1" => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+        _ => Ok(word.to_string()),
+    }
+}
+
+/// Parses the header section of a message into its fields, unfolded and
+/// with encoded-words decoded. Parsing stops at the empty line that ends
+/// the header section.
+pub fn parse_headers(message: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+
+    for line in message.split("\r\n") {
+        if line.is_empty() {
+            break;
+        }
+
+        match fields.last_mut() {
+            Some((_, value)) if line.starts_with([' ', '\t']) => {
+                *value += "\r\n";
+                *value += line;
+            },
+            _ => {
+                let (name, value) = line.split_once(':')
+                    .ok_or_else(|| Error::MessageBuild(format!("Invalid header field: {}", line)))?;
+                fields.push((name.to_string(), value.trim_start().to_string()));
+            },
+        }
+    }
+
+    fields.into_iter()
+        .map(|(name, value)| Ok((name, decode_header_value(value.trim_start())?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn assert_line_lengths(name: &str, value: &str) {
+        let field = format!("{}: {}", name, value);
+        for line in field.split("\r\n") {
+            assert!(line.len() <= LINE_LENGTH, "{:?} is {} characters long", line, line.len());
+        }
+    }
+
+    #[test]
+    fn test_ascii_is_unchanged() {
+        assert_eq!(encode_unstructured("Subject", "Hello, world"), "Hello, world");
+        assert_eq!(encode_address_list("To", &["Bob <bob@example.com>".to_string(), "carol@example.com".to_string()]),
+                   "Bob <bob@example.com>, carol@example.com");
+    }
+
+    #[test]
+    fn test_long_ascii_values_are_folded() {
+        let subject = "Minutes of the quarterly planning meeting, with the action items for every team  attached";
+        let encoded = encode_unstructured("Subject", subject);
+        assert_eq!(encoded, "Minutes of the quarterly planning meeting, with the action items for\r\n every team  attached");
+        assert_eq!(decode_header_value(&encoded).unwrap(), subject);
+
+        let mailboxes: Vec<String> = (1..=4).map(|index| format!("Recipient Number {index} <recipient{index}@example.com>")).collect();
+        let encoded = encode_address_list("To", &mailboxes);
+        assert_eq!(encoded, concat!(
+            "Recipient Number 1 <recipient1@example.com>,\r\n",
+            " Recipient Number 2 <recipient2@example.com>,\r\n",
+            " Recipient Number 3 <recipient3@example.com>,\r\n",
+            " Recipient Number 4 <recipient4@example.com>"));
+        assert_line_lengths("To", &encoded);
+    }
+
+    #[test]
+    fn test_literal_encoded_word_is_encoded() {
+        let subject = "Why =?utf-8?Q?caf=C3=A9?= shows up in subjects";
+        let encoded = encode_unstructured("Subject", subject);
+        assert!(encoded.starts_with("=?utf-8?"));
+        assert_eq!(decode_header_value(&encoded).unwrap(), subject);
+
+        let encoded = encode_address_list("To", &["=?utf-8?B?Qm9i?= <bob@example.com>".to_string()]);
+        assert_eq!(decode_header_value(&encoded).unwrap(), "=?utf-8?B?Qm9i?= <bob@example.com>");
+    }
+
+    #[test]
+    fn test_encoding_choice() {
+        assert_eq!(encode_unstructured("Subject", "Meeting at the café"), "=?utf-8?Q?Meeting_at_the_caf=C3=A9?=");
+        assert_eq!(encode_unstructured("Subject", "Привіт"), "=?utf-8?B?0J/RgNC40LLRltGC?=");
+        assert_eq!(encode_unstructured("Subject", "Soup_of the day = 5? Ask the Café staff"),
+                   "=?utf-8?Q?Soup=5Fof_the_day_=3D_5=3F_Ask_the_Caf=C3=A9_staff?=");
+    }
+
+    #[test]
+    fn test_long_subject_is_folded() {
+        let subject = "Звіт про продажі за третій квартал: усі регіони, включно з Києвом і Львовом";
+        let encoded = encode_unstructured("Subject", subject);
+
+        assert!(encoded.contains("\r\n "));
+        assert!(encoded.split("\r\n ").all(|word| word.starts_with("=?utf-8?B?") && word.len() <= ENCODED_WORD_LENGTH));
+        assert_line_lengths("Subject", &encoded);
+        assert_eq!(decode_header_value(&encoded).unwrap(), subject);
+    }
+
+    #[test]
+    fn test_first_token_is_folded() {
+        let name = "X-Very-Long-Header-Name-That-Leaves-Little-Room-On-The-First-Line";
+        let encoded = encode_unstructured(name, "Привіт, світе");
+
+        assert!(encoded.starts_with("\r\n =?utf-8?B?"));
+        assert_line_lengths(name, &encoded);
+        assert_eq!(parse_headers(&format!("{}: {}\r\n", name, encoded)).unwrap(),
+                   vec![(name.to_string(), "Привіт, світе".to_string())]);
+
+        let mailboxes = vec![format!("{}@example.com", "a".repeat(60)), "Тарас <taras@example.com>".to_string()];
+        let encoded = encode_address_list("Reply-To", &mailboxes);
+        assert!(encoded.starts_with("\r\n aaaa"));
+        assert_line_lengths("Reply-To", &encoded);
+    }
+
+    #[test]
+    fn test_display_names() {
+        let mailboxes = vec!["Олена Шевченко <olena@example.com>".to_string(), "\"José Martinez\" <jose@example.com>".to_string()];
+        let encoded = encode_address_list("To", &mailboxes);
+
+        assert_eq!(encoded, "=?utf-8?B?0J7Qu9C10L3QsCDQqNC10LLRh9C10L3QutC+?= <olena@example.com>,\r\n =?utf-8?Q?Jos=C3=A9_Martinez?= <jose@example.com>");
+        assert_eq!(decode_header_value(&encoded).unwrap(), "Олена Шевченко <olena@example.com>, José Martinez <jose@example.com>");
+    }
+
+    #[test]
+    fn test_decoding() {
+        assert_eq!(decode_header_value("=?ISO-8859-1?q?caf=E9?= au lait").unwrap(), "café au lait");
+        assert_eq!(decode_header_value("=?utf-8*uk?B?0KLQsNC6?= =?utf-8?Q?_=D1=96?=").unwrap(), "Так і");
+        assert_eq!(decode_header_value("(=?koi8-r?B?9MXT1A==?=)").unwrap(), "(=?koi8-r?B?9MXT1A==?=)");
+        assert!(decode_header_value("=?utf-8?Q?bad=Z?=").is_err());
+        assert!(decode_header_value("=?utf-8?Q?sign=+A?=").is_err());
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        let head = "Subject: =?utf-8?B?0J/RgNC40LLRltGC?=\r\n =?utf-8?B?0YHQstGW0YI=?=\r\nTo: bob@example.com\r\n\r\nBody: text";
+
+        assert_eq!(parse_headers(head).unwrap(), vec![
+            ("Subject".to_string(), "Привітсвіт".to_string()),
+            ("To".to_string(), "bob@example.com".to_string()),
+        ]);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_unstructured_round_trip(
+            value in "(\\S\\PC{0,119})?|([!-~]{1,20} ){0,3}=\\?[!->@-~]{1,10}\\?[BbQq]\\?[!->@-~]{0,20}\\?=( [!-~]{1,20}){0,3}",
+            name in "[A-Za-z-]{1,70}",
+        ) {
+            let encoded = encode_unstructured(&name, &value);
+
+            let parsed = parse_headers(&format!("{}: {}\r\n", name, encoded)).unwrap();
+            prop_assert_eq!(parsed, vec![(name.clone(), value.clone())]);
+            if !value.is_ascii() {
+                assert_line_lengths(&name, &encoded);
+            }
+        }
+
+        #[test]
+        fn prop_ascii_words_are_folded(value in "[!-~]{1,20}( {1,2}[!-~]{1,20}){0,30}", name in "[A-Za-z-]{1,50}") {
+            let encoded = encode_unstructured(&name, &value);
+
+            let parsed = parse_headers(&format!("{}: {}\r\n", name, encoded)).unwrap();
+            prop_assert_eq!(parsed, vec![(name.clone(), value.clone())]);
+            assert_line_lengths(&name, &encoded);
+        }
+    }
+}
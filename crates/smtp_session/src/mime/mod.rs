@@ -0,0 +1,241 @@
+use sha2::{Digest, Sha256};
+
+mod encoding;
+mod header;
+
+pub use encoding::{base64_lines, encode_parameter, quoted_printable};
+pub use header::{decode_header_value, encode_address_list, encode_unstructured, parse_headers};
+
+/// Longest line allowed in a message, without its CRLF (RFC 5322 section
+/// 2.1.1).
+const MAX_LINE_LENGTH: usize = 998;
+
+/// The content of a single part, encoded for transfer when it is rendered.
+pub enum PartContent {
+    /// UTF-8 text, sent with CRLF line endings.
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl PartContent {
+    /// Returns the Content-Transfer-Encoding and the encoded content. With
+    /// `binary` set the content only has to survive BINARYMIME (RFC 3030):
+    /// text goes as 8bit or binary and data as it is; otherwise everything
+    /// is encoded to 7bit, so servers without 8BITMIME can relay it.
+    fn encode(&self, binary: bool) -> (&'static str, Vec<u8>) {
+        match self {
+            PartContent::Text(text) if is_7bit(text) => ("7bit", crlf_line_endings(text).into_bytes()),
+            PartContent::Text(text) if binary => {
+                let text = crlf_line_endings(text);
+                let encoding = if text.split("\r\n").all(|line| line.len() <= MAX_LINE_LENGTH) { "8bit" } else { "binary" };
+                (encoding, text.into_bytes())
+            },
+            PartContent::Text(text) => ("quoted-printable", quoted_printable(text).into_bytes()),
+            PartContent::Binary(data) if binary => ("binary", data.clone()),
+            PartContent::Binary(data) => ("base64", base64_lines(data).into_bytes()),
+        }
+    }
+
+    fn is_7bit(&self) -> bool {
+        match self {
+            PartContent::Text(text) => is_7bit(text),
+            PartContent::Binary(_) => false,
+        }
+    }
+}
+
+/// Whether `text` can go as it is with 7bit: ASCII in short enough lines.
+fn is_7bit(text: &str) -> bool {
+    text.is_ascii() && text.lines().all(|line| line.len() <= MAX_LINE_LENGTH)
+}
+
+/// Replaces bare LF and bare CR with CRLF.
+fn crlf_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n").replace('\n', "\r\n")
+}
+
+/// One node of a MIME message body (RFC 2045, RFC 2046).
+pub enum MimePart {
+    Single {
+        headers: Vec<(String, String)>,
+        content: PartContent,
+    },
+    Multipart {
+        subtype: &'static str,
+        parts: Vec<MimePart>,
+    },
+}
+
+impl MimePart {
+    /// A `text/*` part in UTF-8.
+    pub fn text(subtype: &str, text: &str) -> Self {
+        MimePart::Single {
+            headers: vec![("Content-Type".to_string(), format!("text/{}; charset=utf-8", subtype))],
+            content: PartContent::Text(text.to_string()),
+        }
+    }
+
+    /// A part carrying `data`, with the given headers in front of the
+    /// Content-Transfer-Encoding one.
+    pub fn binary(headers: Vec<(String, String)>, data: &[u8]) -> Self {
+        MimePart::Single {
+            headers,
+            content: PartContent::Binary(data.to_vec()),
+        }
+    }
+
+    /// Whether the part and all its subparts can be sent with 7bit as they
+    /// are, so rendering with `binary` set changes nothing.
+    pub fn is_7bit(&self) -> bool {
+        match self {
+            MimePart::Single { content, .. } => content.is_7bit(),
+            MimePart::Multipart { parts, .. } => parts.iter().all(MimePart::is_7bit),
+        }
+    }
+
+    /// Returns the header fields describing the part and its encoded body,
+    /// with `binary` as for `PartContent::encode`.
+    ///
+    /// A multipart boundary is derived from a hash of the encoded subparts,
+    /// so the output is the same every time. Should a subpart contain it
+    /// anyway, the next candidate is hashed with an attempt counter.
+    pub fn render(&self, binary: bool) -> (Vec<(String, String)>, Vec<u8>) {
+        match self {
+            MimePart::Single { headers, content } => {
+                let (transfer_encoding, body) = content.encode(binary);
+                let mut headers = headers.clone();
+                headers.push(("Content-Transfer-Encoding".to_string(), transfer_encoding.to_string()));
+                (headers, body)
+            },
+            MimePart::Multipart { subtype, parts } => {
+                let rendered: Vec<_> = parts.iter().map(|part| part.render(binary)).collect();
+                let parts: Vec<Vec<u8>> = rendered.iter()
+                    .map(|(headers, body)| [format!("{}\r\n", format_headers(headers)).as_bytes(), body].concat())
+                    .collect();
+
+                let candidates = (0u32..).map(|attempt| {
+                    let mut hasher = Sha256::new();
+                    for part in parts.iter() {
+                        hasher.update(part);
+                    }
+                    if attempt > 0 {
+                        hasher.update(attempt.to_be_bytes());
+                    }
+                    let hash: String = hasher.finalize().iter().take(12).map(|byte| format!("{:02x}", byte)).collect();
+                    format!("=_{}_{}", subtype, hash)
+                });
+                let boundary = unused_boundary(candidates, &parts);
+
+                let mut body = Vec::new();
+                for part in parts.iter() {
+                    body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                    body.extend_from_slice(part);
+                    body.extend_from_slice(b"\r\n");
+                }
+                body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+                let mut content_type = format!("multipart/{}; boundary=\"{}\"", subtype, boundary);
+
+                // RFC 2387 requires the type of the root, first, part on multipart/related.
+                let root_type = rendered.first()
+                    .and_then(|(headers, _)| headers.iter().find(|(name, _)| name == "Content-Type"))
+                    .map(|(_, value)| value.split(';').next().unwrap_or_default().trim());
+                if let (&"related", Some(root_type)) = (subtype, root_type) {
+                    content_type += &format!("; type=\"{}\"", root_type);
+                }
+
+                (vec![("Content-Type".to_string(), content_type)], body)
+            },
+        }
+    }
+}
+
+/// The first candidate that no part contains as a delimiter line.
+fn unused_boundary(mut candidates: impl Iterator<Item = String>, parts: &[Vec<u8>]) -> String {
+    candidates.find(|boundary| {
+        let delimiter = format!("--{}", boundary);
+        parts.iter().all(|part| !part.windows(delimiter.len()).any(|window| window == delimiter.as_bytes()))
+    }).unwrap()
+}
+
+/// Formats header fields as `Name: value` lines, each ending with CRLF.
+pub fn format_headers(headers: &[(String, String)]) -> String {
+    headers.iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundary_found_in_a_part_is_skipped() {
+        let candidates = ["=_mixed_a", "=_mixed_b"].map(String::from).into_iter();
+        let parts = [b"Content-Type: text/plain\r\n\r\n--=_mixed_a\r\n".to_vec()];
+        assert_eq!(unused_boundary(candidates, &parts), "=_mixed_b");
+    }
+
+    #[test]
+    fn test_multipart_rendering() {
+        let part = MimePart::Multipart {
+            subtype: "mixed",
+            parts: vec![
+                MimePart::text("plain", "Hello"),
+                MimePart::binary(vec![("Content-Type".to_string(), "application/octet-stream".to_string())], b"\x00\x01"),
+            ],
+        };
+
+        let (headers, body) = part.render(false);
+        let content_type = &headers[0].1;
+        let boundary = content_type.split("boundary=\"").nth(1).unwrap().trim_end_matches('"');
+        assert!(content_type.starts_with("multipart/mixed; "));
+
+        assert_eq!(String::from_utf8(body.clone()).unwrap(), format!(concat!(
+            "--{b}\r\n",
+            "Content-Type: text/plain; charset=utf-8\r\n",
+            "Content-Transfer-Encoding: 7bit\r\n",
+            "\r\n",
+            "Hello\r\n",
+            "--{b}\r\n",
+            "Content-Type: application/octet-stream\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "AAE=\r\n",
+            "--{b}--\r\n"), b = boundary));
+
+        assert_eq!(part.render(false), (headers, body));
+    }
+
+    #[test]
+    fn test_text_transfer_encoding() {
+        let (headers, body) = MimePart::text("plain", "Grüße").render(false);
+        assert_eq!(headers[1].1, "quoted-printable");
+        assert_eq!(body, b"Gr=C3=BC=C3=9Fe");
+
+        let (headers, body) = MimePart::text("plain", &"a".repeat(999)).render(false);
+        assert_eq!(headers[1].1, "quoted-printable");
+        assert!(body.split(|&byte| byte == b'\n').all(|line| line.len() <= 77));
+
+        let (headers, body) = MimePart::text("plain", &format!("{}\n.", "a".repeat(998))).render(false);
+        assert_eq!(headers[1].1, "7bit");
+        assert_eq!(body, format!("{}\r\n.", "a".repeat(998)).into_bytes());
+    }
+
+    #[test]
+    fn test_binary_rendering() {
+        let (headers, body) = MimePart::text("plain", "Grüße\nund\rmehr").render(true);
+        assert_eq!(headers[1].1, "8bit");
+        assert_eq!(body, "Grüße\r\nund\r\nmehr".as_bytes());
+
+        let (headers, _) = MimePart::text("plain", &"ü".repeat(500)).render(true);
+        assert_eq!(headers[1].1, "binary");
+
+        let attachment = MimePart::binary(vec![("Content-Type".to_string(), "application/octet-stream".to_string())], b"\x00\n\xff");
+        assert!(!attachment.is_7bit());
+        assert_eq!(attachment.render(true).1, b"\x00\n\xff");
+        assert_eq!(attachment.render(false).1, b"AAr/");
+
+        assert!(MimePart::text("plain", "Hello").is_7bit());
+    }
+}
@@ -0,0 +1,141 @@
+use error_handler::{EnhancedStatusCode, Error, SmtpReplyError, SmtpStatus};
+
+use crate::{SmtpCommand, SmtpResponse};
+
+/// What `SmtpSession::send_message` does when the server rejects some of
+/// the recipients.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RecipientPolicy {
+    /// Deliver to the accepted recipients; fail only if none was accepted.
+    #[default]
+    AcceptPartial,
+    /// Fail without sending the message unless every recipient was accepted.
+    AllOrNothing,
+}
+
+/// The server's reply to the RCPT TO command of one recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecipientStatus {
+    pub address: String,
+    pub code: u16,
+    pub enhanced_code: Option<EnhancedStatusCode>,
+    /// The reply text, without the enhanced code at the start of each line.
+    pub text: String,
+}
+
+impl RecipientStatus {
+    fn from_response(address: &str, response: &SmtpResponse) -> Self {
+        Self {
+            address: address.to_string(),
+            code: response.get_code(),
+            enhanced_code: response.get_enhanced_code(),
+            text: response.get_text_after_enhanced_code(),
+        }
+    }
+
+    /// The rejection as an error, as a failed RCPT TO would report it.
+    pub fn to_error(&self) -> Error {
+        Error::SmtpReply(SmtpReplyError {
+            command: Some(SmtpCommand::RcptTo),
+            expected: SmtpStatus::PositiveCompletion,
+            code: self.code,
+            enhanced_code: self.enhanced_code,
+            text: self.text.clone(),
+        })
+    }
+}
+
+/// Outcome of a sent message, recipient by recipient.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SendReport {
+    m_message_id: String,
+    m_accepted: Vec<RecipientStatus>,
+    m_rejected: Vec<RecipientStatus>,
+}
+
+impl SendReport {
+    pub(crate) fn add_recipient(&mut self, address: &str, response: &SmtpResponse) {
+        let status = RecipientStatus::from_response(address, response);
+
+        if response.get_status() == SmtpStatus::PositiveCompletion {
+            self.m_accepted.push(status);
+        } else {
+            self.m_rejected.push(status);
+        }
+    }
+
+    pub(crate) fn set_message_id(&mut self, message_id: &str) {
+        self.m_message_id = message_id.to_string();
+    }
+
+    /// The error that stops the transaction under `policy`, if any.
+    pub(crate) fn check(&self, policy: RecipientPolicy) -> Result<(), Error> {
+        let Some(first_rejected) = self.m_rejected.first() else { return Ok(()); };
+
+        match policy {
+            RecipientPolicy::AcceptPartial if !self.m_accepted.is_empty() => Ok(()),
+            _ => Err(first_rejected.to_error()),
+        }
+    }
+
+    /// The Message-ID the message was sent with, without angle brackets.
+    pub fn get_message_id(&self) -> &str {
+        &self.m_message_id
+    }
+
+    pub fn get_accepted(&self) -> &[RecipientStatus] {
+        &self.m_accepted
+    }
+
+    pub fn get_rejected(&self) -> &[RecipientStatus] {
+        &self.m_rejected
+    }
+
+    /// Returns `true` when every recipient was accepted.
+    pub fn is_complete(&self) -> bool {
+        self.m_rejected.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp_response::SmtpResponseBuilder;
+
+    fn report(replies: &[(&str, &str)]) -> SendReport {
+        let mut report = SendReport::default();
+        for (address, reply) in replies {
+            report.add_recipient(address, &SmtpResponseBuilder::new().build(reply).unwrap());
+        }
+        report
+    }
+
+    #[test]
+    fn test_partial_acceptance() {
+        let report = report(&[
+            ("bob@example.com", "250 2.1.5 OK\r\n"),
+            ("nobody@example.com", "550 5.1.1 No such user\r\n"),
+        ]);
+
+        assert_eq!(report.get_accepted().len(), 1);
+        assert_eq!(report.get_rejected(), &[RecipientStatus {
+            address: "nobody@example.com".to_string(),
+            code: 550,
+            enhanced_code: Some(EnhancedStatusCode::new(5, 1, 1)),
+            text: "No such user".to_string(),
+        }]);
+        assert!(!report.is_complete());
+
+        assert_eq!(report.check(RecipientPolicy::AcceptPartial), Ok(()));
+        assert_eq!(report.check(RecipientPolicy::AllOrNothing), Err(report.get_rejected()[0].to_error()));
+    }
+
+    #[test]
+    fn test_no_recipient_accepted() {
+        let report = report(&[("nobody@example.com", "450 4.2.1 Mailbox busy\r\n")]);
+
+        let result = report.check(RecipientPolicy::AcceptPartial);
+        assert!(matches!(&result, Err(Error::SmtpReply(reply)) if reply.code == 450));
+        assert!(result.unwrap_err().is_retryable());
+    }
+}
@@ -0,0 +1,135 @@
+use rand::Rng;
+use tokio::time::Duration;
+
+/// How `SmtpSession::send_message` retries after transient failures.
+///
+/// The delay before attempt `n + 1` grows as `initial_backoff * 2^(n - 1)`,
+/// capped at `max_backoff`. With jitter enabled a random delay between half
+/// and all of that value is used, so clients failing together do not retry
+/// in lockstep.
+///
+/// Once the end of the message data has been sent the server may already
+/// have accepted it, so by default a timeout or dropped connection from then
+/// on is not retried to avoid delivering the message twice. A 4xx reply
+/// still is, as it shows the message was not accepted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    m_max_attempts: u32,
+    m_initial_backoff: Duration,
+    m_max_backoff: Duration,
+    m_jitter: bool,
+    m_retry_after_data_termination: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            m_max_attempts: 3,
+            m_initial_backoff: Duration::from_secs(1),
+            m_max_backoff: Duration::from_secs(30),
+            m_jitter: true,
+            m_retry_after_data_termination: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that makes a single attempt.
+    pub fn no_retry() -> Self {
+        Self::default().max_attempts(1)
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.m_max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.m_initial_backoff = initial_backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.m_max_backoff = max_backoff;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.m_jitter = jitter;
+        self
+    }
+
+    /// Also retries timeouts and dropped connections after the end of the
+    /// message data was sent, accepting that the message may then be
+    /// delivered more than once.
+    pub fn retry_after_data_termination(mut self, retry_after_data_termination: bool) -> Self {
+        self.m_retry_after_data_termination = retry_after_data_termination;
+        self
+    }
+
+    pub fn get_retry_after_data_termination(&self) -> bool {
+        self.m_retry_after_data_termination
+    }
+
+    pub fn get_max_attempts(&self) -> u32 {
+        self.m_max_attempts
+    }
+
+    /// Returns the delay to wait after the failed attempt number `attempt`
+    /// (starting at 1) before the next one.
+    pub fn get_backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let backoff = self.m_initial_backoff
+            .saturating_mul(1u32 << exponent)
+            .min(self.m_max_backoff);
+
+        if self.m_jitter {
+            backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+        } else {
+            backoff
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = RetryPolicy::default()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_secs(1))
+            .jitter(false);
+
+        assert_eq!(policy.get_backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.get_backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.get_backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.get_backoff(5), Duration::from_secs(1));
+        assert_eq!(policy.get_backoff(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let policy = RetryPolicy::default()
+            .initial_backoff(Duration::from_millis(400))
+            .max_backoff(Duration::from_secs(10));
+
+        for _ in 0..100 {
+            let backoff = policy.get_backoff(2);
+            assert!(backoff >= Duration::from_millis(400) && backoff <= Duration::from_millis(800));
+        }
+    }
+
+    #[test]
+    fn test_no_retry() {
+        assert_eq!(RetryPolicy::no_retry().get_max_attempts(), 1);
+        assert_eq!(RetryPolicy::default().max_attempts(0).get_max_attempts(), 1);
+    }
+
+    #[test]
+    fn test_no_retry_after_data_termination_by_default() {
+        assert!(!RetryPolicy::default().get_retry_after_data_termination());
+        assert!(RetryPolicy::default().retry_after_data_termination(true).get_retry_after_data_termination());
+    }
+}
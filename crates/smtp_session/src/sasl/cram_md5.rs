@@ -0,0 +1,56 @@
+use error_handler::Error;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+
+use super::SaslMechanism;
+use crate::AuthMechanism;
+
+/// CRAM-MD5 (RFC 2195): the password never leaves the client, only an
+/// HMAC-MD5 of the server challenge keyed with it.
+pub struct CramMd5 {
+    m_username: String,
+    m_password: String,
+}
+
+impl CramMd5 {
+    pub fn new(username: &str, password: &str) -> Self {
+        Self {
+            m_username: username.to_string(),
+            m_password: password.to_string(),
+        }
+    }
+}
+
+impl SaslMechanism for CramMd5 {
+    fn get_mechanism(&self) -> AuthMechanism {
+        AuthMechanism::CramMd5
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut mac = Hmac::<Md5>::new_from_slice(self.m_password.as_bytes())
+            .map_err(|_| Error::Sasl("CRAM-MD5: invalid key".to_string()))?;
+        mac.update(challenge);
+
+        let digest: String = mac.finalize().into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        Ok(format!("{} {}", self.m_username, digest).into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc2195_example() {
+        let mut mechanism = CramMd5::new("tim", "tanstaaftanstaaf");
+
+        assert_eq!(mechanism.initial_response(), Ok(None));
+        let response = mechanism.respond(b"<1896.697170952@postoffice.reston.mci.net>").unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), "tim b913a602c7eda7a495b4e6e7334d3890");
+        assert_eq!(mechanism.verify_completion(), Ok(()));
+    }
+}
@@ -0,0 +1,90 @@
+use error_handler::Error;
+
+use crate::AuthMechanism;
+
+mod cram_md5;
+mod oauth2;
+mod scram;
+
+pub use cram_md5::CramMd5;
+pub use oauth2::{OAuthBearer, XOAuth2};
+pub use scram::Scram;
+
+/// Secrets `SmtpSession::authenticate_auto` can authenticate with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Credentials {
+    Password { username: String, password: String },
+    OAuth2 { username: String, access_token: String },
+}
+
+impl Credentials {
+    pub fn password(username: &str, password: &str) -> Self {
+        Credentials::Password { username: username.to_string(), password: password.to_string() }
+    }
+
+    pub fn oauth2(username: &str, access_token: &str) -> Self {
+        Credentials::OAuth2 { username: username.to_string(), access_token: access_token.to_string() }
+    }
+
+    /// Mechanisms usable with these credentials, strongest first.
+    pub fn get_mechanisms(&self) -> &'static [AuthMechanism] {
+        match self {
+            Credentials::Password { .. } => &[
+                AuthMechanism::ScramSha256,
+                AuthMechanism::ScramSha1,
+                AuthMechanism::CramMd5,
+                AuthMechanism::Plain,
+                AuthMechanism::Login,
+            ],
+            Credentials::OAuth2 { .. } => &[
+                AuthMechanism::OAuthBearer,
+                AuthMechanism::XOAuth2,
+            ],
+        }
+    }
+}
+
+/// A SASL mechanism driven by `SmtpSession::authenticate_with`.
+///
+/// Challenges and responses are exchanged already base64 decoded; the
+/// session takes care of the encoding on the wire.
+pub trait SaslMechanism: Send {
+    fn get_mechanism(&self) -> AuthMechanism;
+
+    /// Data sent along with the AUTH command, if the mechanism starts the
+    /// exchange itself.
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    /// Answers one server challenge from a 334 reply.
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Called once the server accepted the authentication. Mechanisms that
+    /// authenticate the server as well fail here if they could not.
+    fn verify_completion(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// A more specific error than the server's final reply, for mechanisms
+    /// whose challenges explain why the authentication is failing.
+    fn failure(&self) -> Option<Error> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mechanisms_strongest_first() {
+        let mechanisms = Credentials::password("user", "pencil").get_mechanisms();
+        assert_eq!(mechanisms.first(), Some(&AuthMechanism::ScramSha256));
+        assert!(!mechanisms.contains(&AuthMechanism::XOAuth2));
+
+        let mechanisms = Credentials::oauth2("user", "token").get_mechanisms();
+        assert_eq!(mechanisms, &[AuthMechanism::OAuthBearer, AuthMechanism::XOAuth2]);
+        assert!(mechanisms.iter().all(AuthMechanism::is_plaintext));
+    }
+}
@@ -0,0 +1,178 @@
+use error_handler::{Error, OAuth2Error};
+
+use super::SaslMechanism;
+use crate::AuthMechanism;
+
+/// Parses the JSON error a server sends as its challenge when the token
+/// is rejected.
+fn parse_error_challenge(challenge: &[u8]) -> OAuth2Error {
+    let json: serde_json::Value = serde_json::from_slice(challenge).unwrap_or_default();
+    let field = |name: &str| match json.get(name) {
+        Some(serde_json::Value::String(value)) => Some(value.clone()),
+        Some(serde_json::Value::Null) | None => None,
+        Some(value) => Some(value.to_string()),
+    };
+
+    OAuth2Error {
+        status: field("status").unwrap_or_else(|| String::from_utf8_lossy(challenge).into_owned()),
+        schemes: field("schemes"),
+        scope: field("scope"),
+        openid_configuration: field("openid-configuration"),
+    }
+}
+
+/// Google's and Microsoft's XOAUTH2 mechanism.
+pub struct XOAuth2 {
+    m_username: String,
+    m_access_token: String,
+    m_error: Option<OAuth2Error>,
+}
+
+impl XOAuth2 {
+    pub fn new(username: &str, access_token: &str) -> Self {
+        Self {
+            m_username: username.to_string(),
+            m_access_token: access_token.to_string(),
+            m_error: None,
+        }
+    }
+}
+
+impl SaslMechanism for XOAuth2 {
+    fn get_mechanism(&self) -> AuthMechanism {
+        AuthMechanism::XOAuth2
+    }
+
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(Some(format!("user={}\x01auth=Bearer {}\x01\x01", self.m_username, self.m_access_token).into_bytes()))
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        // The only challenge is the error report; an empty answer lets the
+        // server finish with its failure reply.
+        self.m_error = Some(parse_error_challenge(challenge));
+        Ok(Vec::new())
+    }
+
+    fn failure(&self) -> Option<Error> {
+        self.m_error.clone().map(Error::OAuth2)
+    }
+}
+
+/// OAUTHBEARER (RFC 7628).
+pub struct OAuthBearer {
+    m_username: String,
+    m_access_token: String,
+    m_host: Option<String>,
+    m_port: Option<u16>,
+    m_error: Option<OAuth2Error>,
+}
+
+impl OAuthBearer {
+    pub fn new(username: &str, access_token: &str) -> Self {
+        Self {
+            m_username: username.to_string(),
+            m_access_token: access_token.to_string(),
+            m_host: None,
+            m_port: None,
+            m_error: None,
+        }
+    }
+
+    pub fn server(mut self, host: &str, port: u16) -> Self {
+        self.m_host = Some(host.to_string());
+        self.m_port = Some(port);
+        self
+    }
+}
+
+impl SaslMechanism for OAuthBearer {
+    fn get_mechanism(&self) -> AuthMechanism {
+        AuthMechanism::OAuthBearer
+    }
+
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let username = self.m_username.replace('=', "=3D").replace(',', "=2C");
+        let mut response = format!("n,a={},\x01", username);
+
+        if let Some(host) = &self.m_host {
+            response.push_str(&format!("host={}\x01", host));
+        }
+        if let Some(port) = self.m_port {
+            response.push_str(&format!("port={}\x01", port));
+        }
+
+        response.push_str(&format!("auth=Bearer {}\x01\x01", self.m_access_token));
+        Ok(Some(response.into_bytes()))
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        // RFC 7628 section 3.2.3: the error is acknowledged with a lone %x01.
+        self.m_error = Some(parse_error_challenge(challenge));
+        Ok(vec![0x01])
+    }
+
+    fn failure(&self) -> Option<Error> {
+        self.m_error.clone().map(Error::OAuth2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xoauth2_initial_response() {
+        let mut mechanism = XOAuth2::new("someuser@example.com", "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg");
+        let initial = mechanism.initial_response().unwrap().unwrap();
+
+        assert_eq!(crate::base64::encode_bytes(&initial),
+            "dXNlcj1zb21ldXNlckBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciB5YTI5LnZGOWRmdDRxbVRjMk52YjNSbGNrQmhkSFJoZG1semRHRXVZMjl0Q2cBAQ==");
+    }
+
+    #[test]
+    fn test_xoauth2_error_challenge() {
+        let mut mechanism = XOAuth2::new("someuser@example.com", "expired");
+        mechanism.initial_response().unwrap();
+        assert_eq!(mechanism.failure(), None);
+
+        let challenge = crate::base64::decode("eyJzdGF0dXMiOiI0MDEiLCJzY2hlbWVzIjoiQmVhcmVyIiwic2NvcGUiOiJodHRwczovL21haWwuZ29vZ2xlLmNvbS8ifQ==").unwrap();
+        assert_eq!(mechanism.respond(&challenge), Ok(Vec::new()));
+        assert_eq!(mechanism.failure(), Some(Error::OAuth2(OAuth2Error {
+            status: "401".to_string(),
+            schemes: Some("Bearer".to_string()),
+            scope: Some("https://mail.google.com/".to_string()),
+            openid_configuration: None,
+        })));
+    }
+
+    #[test]
+    fn test_oauthbearer_initial_response() {
+        let mut mechanism = OAuthBearer::new("user@example.com", "vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==")
+            .server("server.example.com", 587);
+        let initial = mechanism.initial_response().unwrap().unwrap();
+
+        assert_eq!(String::from_utf8(initial).unwrap(),
+            "n,a=user@example.com,\x01host=server.example.com\x01port=587\x01auth=Bearer vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01");
+    }
+
+    #[test]
+    fn test_oauthbearer_error_challenge() {
+        let mut mechanism = OAuthBearer::new("user@example.com", "expired");
+        mechanism.initial_response().unwrap();
+
+        let challenge = br#"{"status":"invalid_token","scope":"example_scope","openid-configuration":"https://example.com/.well-known/openid-configuration"}"#;
+        assert_eq!(mechanism.respond(challenge), Ok(vec![0x01]));
+        assert_eq!(mechanism.failure(), Some(Error::OAuth2(OAuth2Error {
+            status: "invalid_token".to_string(),
+            schemes: None,
+            scope: Some("example_scope".to_string()),
+            openid_configuration: Some("https://example.com/.well-known/openid-configuration".to_string()),
+        })));
+    }
+
+    #[test]
+    fn test_non_json_challenge_keeps_raw_text() {
+        assert_eq!(parse_error_challenge(b"token expired").status, "token expired");
+    }
+}
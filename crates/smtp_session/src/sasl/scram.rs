@@ -0,0 +1,289 @@
+use error_handler::Error;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use super::SaslMechanism;
+use crate::{base64, AuthMechanism};
+
+#[derive(Clone, Copy)]
+enum ScramHash {
+    Sha1,
+    Sha256,
+}
+
+impl ScramHash {
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+            ScramHash::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+        }
+    }
+
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => Sha1::digest(data).to_vec(),
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn salted_password(self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => pbkdf2::pbkdf2_hmac_array::<Sha1, 20>(password, salt, iterations).to_vec(),
+            ScramHash::Sha256 => pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password, salt, iterations).to_vec(),
+        }
+    }
+}
+
+enum ScramState {
+    Initial,
+    ClientFirstSent { client_first_bare: String },
+    ClientFinalSent { server_signature: Vec<u8> },
+    Verified,
+}
+
+/// SCRAM-SHA-1 and SCRAM-SHA-256 (RFC 5802, RFC 7677) without channel
+/// binding. Both sides prove knowledge of the password, and the server's
+/// final message is checked before the authentication is considered done.
+pub struct Scram {
+    m_hash: ScramHash,
+    m_username: String,
+    m_password: String,
+    m_client_nonce: String,
+    m_state: ScramState,
+}
+
+impl Scram {
+    pub fn sha1(username: &str, password: &str) -> Self {
+        Self::with_client_nonce(ScramHash::Sha1, username, password, Self::generate_nonce())
+    }
+
+    pub fn sha256(username: &str, password: &str) -> Self {
+        Self::with_client_nonce(ScramHash::Sha256, username, password, Self::generate_nonce())
+    }
+
+    fn with_client_nonce(hash: ScramHash, username: &str, password: &str, client_nonce: String) -> Self {
+        Self {
+            m_hash: hash,
+            m_username: username.to_string(),
+            m_password: password.to_string(),
+            m_client_nonce: client_nonce,
+            m_state: ScramState::Initial,
+        }
+    }
+
+    fn generate_nonce() -> String {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect()
+    }
+
+    /// RFC 5802 section 5.1: `=` and `,` are escaped in the username.
+    fn escape_username(username: &str) -> String {
+        username.replace('=', "=3D").replace(',', "=2C")
+    }
+
+    fn parse_attributes(message: &str) -> Result<Vec<(char, &str)>, Error> {
+        message.split(',')
+            .map(|attribute| {
+                let mut chars = attribute.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(name), Some('=')) => Ok((name, &attribute[2..])),
+                    _ => Err(Error::Sasl(format!("SCRAM: malformed server message: {}", message))),
+                }
+            })
+            .collect()
+    }
+
+    fn client_final(&mut self, client_first_bare: &str, server_first: &str) -> Result<Vec<u8>, Error> {
+        let attributes = Self::parse_attributes(server_first)?;
+        let find = |name: char| attributes.iter().find(|(key, _)| *key == name).map(|(_, value)| *value);
+
+        if find('m').is_some() {
+            return Err(Error::Sasl("SCRAM: unsupported mandatory extension".to_string()));
+        }
+
+        let nonce = find('r').ok_or_else(|| Error::Sasl("SCRAM: missing server nonce".to_string()))?;
+        if !nonce.starts_with(&self.m_client_nonce) || nonce.len() == self.m_client_nonce.len() {
+            return Err(Error::Sasl("SCRAM: server nonce does not extend the client nonce".to_string()));
+        }
+
+        let salt = base64::decode(find('s').ok_or_else(|| Error::Sasl("SCRAM: missing salt".to_string()))?)
+            .map_err(|_| Error::Sasl("SCRAM: invalid salt".to_string()))?;
+        let iterations: u32 = find('i')
+            .and_then(|iterations| iterations.parse().ok())
+            .filter(|iterations| *iterations > 0)
+            .ok_or_else(|| Error::Sasl("SCRAM: invalid iteration count".to_string()))?;
+
+        let client_final_without_proof = format!("c={},r={}", base64::encode("n,,"), nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, client_final_without_proof);
+
+        let salted_password = self.m_hash.salted_password(self.m_password.as_bytes(), &salt, iterations);
+        let client_key = self.m_hash.hmac(&salted_password, b"Client Key");
+        let stored_key = self.m_hash.hash(&client_key);
+        let client_signature = self.m_hash.hmac(&stored_key, auth_message.as_bytes());
+        let client_proof: Vec<u8> = client_key.iter()
+            .zip(client_signature.iter())
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+
+        let server_key = self.m_hash.hmac(&salted_password, b"Server Key");
+        let server_signature = self.m_hash.hmac(&server_key, auth_message.as_bytes());
+
+        self.m_state = ScramState::ClientFinalSent { server_signature };
+        Ok(format!("{},p={}", client_final_without_proof, base64::encode_bytes(&client_proof)).into_bytes())
+    }
+
+    fn verify_server_final(&mut self, server_signature: &[u8], server_final: &str) -> Result<Vec<u8>, Error> {
+        let attributes = Self::parse_attributes(server_final)?;
+
+        for (name, value) in attributes {
+            match name {
+                'e' => return Err(Error::Sasl(format!("SCRAM: server reported an error: {}", value))),
+                'v' => {
+                    let signature = base64::decode(value)
+                        .map_err(|_| Error::Sasl("SCRAM: invalid server signature".to_string()))?;
+                    if signature != server_signature {
+                        return Err(Error::Sasl("SCRAM: server signature mismatch".to_string()));
+                    }
+                    self.m_state = ScramState::Verified;
+                    return Ok(Vec::new());
+                },
+                _ => {},
+            }
+        }
+
+        Err(Error::Sasl("SCRAM: missing server signature".to_string()))
+    }
+}
+
+impl SaslMechanism for Scram {
+    fn get_mechanism(&self) -> AuthMechanism {
+        match self.m_hash {
+            ScramHash::Sha1 => AuthMechanism::ScramSha1,
+            ScramHash::Sha256 => AuthMechanism::ScramSha256,
+        }
+    }
+
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let client_first_bare = format!("n={},r={}", Self::escape_username(&self.m_username), self.m_client_nonce);
+        let client_first = format!("n,,{}", client_first_bare);
+
+        self.m_state = ScramState::ClientFirstSent { client_first_bare };
+        Ok(Some(client_first.into_bytes()))
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        let challenge = std::str::from_utf8(challenge)
+            .map_err(|_| Error::Sasl("SCRAM: server message is not UTF-8".to_string()))?;
+
+        match std::mem::replace(&mut self.m_state, ScramState::Initial) {
+            ScramState::ClientFirstSent { client_first_bare } => self.client_final(&client_first_bare, challenge),
+            ScramState::ClientFinalSent { server_signature } => self.verify_server_final(&server_signature, challenge),
+            _ => Err(Error::Sasl("SCRAM: unexpected server challenge".to_string())),
+        }
+    }
+
+    fn verify_completion(&self) -> Result<(), Error> {
+        match self.m_state {
+            ScramState::Verified => Ok(()),
+            _ => Err(Error::Sasl("SCRAM: server signature was not verified".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays the server side of an exchange, checking every client message.
+    fn run_scripted_exchange(mechanism: &mut Scram, script: &[(&str, &str)]) -> Result<(), Error> {
+        let mut client_message = mechanism.initial_response()?.unwrap();
+
+        for (expected_client_message, server_message) in script {
+            assert_eq!(String::from_utf8(client_message).unwrap(), *expected_client_message);
+            client_message = mechanism.respond(server_message.as_bytes())?;
+        }
+
+        assert!(client_message.is_empty());
+        mechanism.verify_completion()
+    }
+
+    #[test]
+    fn test_rfc5802_sha1_example() {
+        let mut mechanism = Scram::with_client_nonce(ScramHash::Sha1, "user", "pencil", "fyko+d2lbbFgONRv9qkxdawL".to_string());
+
+        let result = run_scripted_exchange(&mut mechanism, &[
+            ("n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL",
+             "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096"),
+            ("c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=",
+             "v=rmF9pqV8S7suAoZWja4dJRkFsKQ="),
+        ]);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(mechanism.get_mechanism(), AuthMechanism::ScramSha1);
+    }
+
+    #[test]
+    fn test_rfc7677_sha256_example() {
+        let mut mechanism = Scram::with_client_nonce(ScramHash::Sha256, "user", "pencil", "rOprNGfwEbeRWgbNEkqO".to_string());
+
+        let result = run_scripted_exchange(&mut mechanism, &[
+            ("n,,n=user,r=rOprNGfwEbeRWgbNEkqO",
+             "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"),
+            ("c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+             "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="),
+        ]);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(mechanism.get_mechanism(), AuthMechanism::ScramSha256);
+    }
+
+    #[test]
+    fn test_forged_server_signature_is_rejected() {
+        let mut mechanism = Scram::with_client_nonce(ScramHash::Sha256, "user", "pencil", "rOprNGfwEbeRWgbNEkqO".to_string());
+        mechanism.initial_response().unwrap();
+        mechanism.respond(b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096").unwrap();
+
+        let result = mechanism.respond(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        assert_eq!(result, Err(Error::Sasl("SCRAM: server signature mismatch".to_string())));
+        assert!(mechanism.verify_completion().is_err());
+    }
+
+    #[test]
+    fn test_server_nonce_must_extend_client_nonce() {
+        let mut mechanism = Scram::with_client_nonce(ScramHash::Sha1, "user", "pencil", "clientnonce".to_string());
+        mechanism.initial_response().unwrap();
+
+        let result = mechanism.respond(b"r=othernonce123,s=QSXCR+Q6sek8bf92,i=4096");
+        assert_eq!(result, Err(Error::Sasl("SCRAM: server nonce does not extend the client nonce".to_string())));
+    }
+
+    #[test]
+    fn test_server_error_and_missing_final_message() {
+        let mut mechanism = Scram::with_client_nonce(ScramHash::Sha1, "user", "pencil", "fyko+d2lbbFgONRv9qkxdawL".to_string());
+        mechanism.initial_response().unwrap();
+        mechanism.respond(b"r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096").unwrap();
+
+        assert_eq!(mechanism.verify_completion(), Err(Error::Sasl("SCRAM: server signature was not verified".to_string())));
+        assert_eq!(mechanism.respond(b"e=invalid-proof"), Err(Error::Sasl("SCRAM: server reported an error: invalid-proof".to_string())));
+    }
+
+    #[test]
+    fn test_username_escaping() {
+        let mut mechanism = Scram::with_client_nonce(ScramHash::Sha1, "a=b,c", "pencil", "nonce".to_string());
+        let client_first = mechanism.initial_response().unwrap().unwrap();
+        assert_eq!(String::from_utf8(client_first).unwrap(), "n,,n=a=3Db=2Cc,r=nonce");
+    }
+}
@@ -1,32 +1,17 @@
-use error_handler::Error;
+use error_handler::{Error, SmtpReplyError};
 use regex::Regex;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum SmtpStatus {
-    PositiveCompletion,
-    PositiveIntermediate,
-    TransientNegativeCompletion,
-    PermanentNegativeCompletion,
-    Unknown,
-}
+use crate::SmtpCommand;
 
-impl From<u16> for SmtpStatus {
-    fn from(status: u16) -> Self {
-        match status - (status % 100) {
-            200 => SmtpStatus::PositiveCompletion,
-            300 => SmtpStatus::PositiveIntermediate,
-            400 => SmtpStatus::TransientNegativeCompletion,
-            500 => SmtpStatus::PermanentNegativeCompletion,
-            _ => SmtpStatus::Unknown,
-        }
-    }
-}
+pub use error_handler::{EnhancedStatusCode, SmtpStatus};
 
 #[derive(Debug, PartialEq)]
 pub struct SmtpResponse {
     m_raw_response: String,
+    m_code: u16,
     m_status: SmtpStatus,
-    m_text: String,
+    m_enhanced_code: Option<EnhancedStatusCode>,
+    m_lines: Vec<String>,
 }
 
 #[allow(dead_code)]
@@ -35,21 +20,58 @@ impl SmtpResponse {
         self.m_raw_response.clone()
     }
 
+    pub fn get_code(&self) -> u16 {
+        self.m_code
+    }
+
     pub fn get_status(&self) -> SmtpStatus {
-        self.m_status.clone()
+        self.m_status
+    }
+
+    pub fn get_enhanced_code(&self) -> Option<EnhancedStatusCode> {
+        self.m_enhanced_code
     }
 
+    /// Returns the text of every reply line joined with `\n`.
     pub fn get_text(&self) -> String {
-        self.m_text.clone()
+        self.m_lines.join("\n")
     }
 
-    pub fn status_should_be(&self, status: SmtpStatus) -> Result<(), Error> {
+    pub fn get_text_lines(&self) -> Vec<String> {
+        self.m_lines.clone()
+    }
+
+    /// Checks the reply status, naming `command` (`None` for the greeting)
+    /// in the error when it does not match.
+    pub fn status_should_be(&self, command: Option<&SmtpCommand>, status: SmtpStatus) -> Result<(), Error> {
         if self.m_status == status {
             Ok(())
         } else {
-            Err(Error::SmtpResponse("Unexpected status".to_string()))
+            Err(Error::SmtpReply(SmtpReplyError {
+                command: command.copied(),
+                expected: status,
+                code: self.m_code,
+                enhanced_code: self.m_enhanced_code,
+                text: self.get_text_after_enhanced_code(),
+            }))
         }
     }
+
+    /// Returns the text like `get_text`, with the enhanced code that starts
+    /// each line removed, as it is reported separately.
+    pub fn get_text_after_enhanced_code(&self) -> String {
+        let Some(enhanced_code) = self.m_enhanced_code else {
+            return self.get_text();
+        };
+
+        self.m_lines.iter()
+            .map(|line| match EnhancedStatusCode::parse(line) {
+                Some(code) if code == enhanced_code => line.split_once(char::is_whitespace).map_or("", |(_, text)| text.trim_start()),
+                _ => line.as_str(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 }
 
 pub struct SmtpResponseBuilder {
@@ -59,47 +81,72 @@ pub struct SmtpResponseBuilder {
 impl SmtpResponseBuilder {
     pub fn new() -> Self {
         Self {
-            m_regex: r"(\d{3})(?:[ -](\d\.\d\.\d))?[ -](.*)".to_string(),
+            m_regex: r"^(\d{3})(?:([ -])(.*))?$".to_string(),
         }
     }
 
     pub fn build(&self, raw_response: &str) -> Result<SmtpResponse, Error> {
         let status_code = self.parse_status_code(raw_response)?;
         let status = SmtpStatus::from(status_code);
-        let text = self.parse_text(raw_response)?;
+        let lines: Vec<String> = self.parse_lines(raw_response)?
+            .into_iter()
+            .map(|(_, _, text)| text)
+            .collect();
+        let enhanced_code = self.parse_enhanced_code(status_code, &lines[0]);
 
         Ok(SmtpResponse {
             m_raw_response: raw_response.to_string(),
+            m_code: status_code,
             m_status: status,
-            m_text: text,
+            m_enhanced_code: enhanced_code,
+            m_lines: lines,
         })
     }
 
     fn parse_status_code(&self, raw_response: &str) -> Result<u16, Error> {
-        if self.is_valid_response(raw_response) {
-            let re = Regex::new(r"(\d{3})").unwrap();
-            let caps = re.captures(raw_response).unwrap();
-            
-            Ok(caps.get(1).unwrap().as_str().parse::<u16>().unwrap())
-        } else {
-            Err(Error::SmtpResponse("Invalid response".to_string()))
-        }
-
+        let lines = self.parse_lines(raw_response)?;
+        Ok(lines[0].0)
     }
 
-    fn parse_text(&self, raw_response: &str) -> Result<String, Error> {
-        if self.is_valid_response(raw_response) {
-            let re = Regex::new(&self.m_regex).unwrap();
-            let caps = re.captures(raw_response).unwrap();
-            Ok(caps.get(3).unwrap().as_str().to_string())
-        } else {
-            Err(Error::SmtpResponse("Invalid response".to_string()))
-        }
+    /// RFC 3463 section 2: an enhanced code whose class differs from the
+    /// first digit of the reply code must be ignored.
+    fn parse_enhanced_code(&self, status_code: u16, text: &str) -> Option<EnhancedStatusCode> {
+        EnhancedStatusCode::parse(text)
+            .filter(|enhanced_code| u16::from(enhanced_code.class) == status_code / 100)
     }
 
-    fn is_valid_response(&self, raw_response: &str) -> bool {
+    /// Splits a reply into `(code, is_last, text)` lines and checks that it is
+    /// a single well-formed reply: every line carries the same code, and only
+    /// the last line lacks the `-` continuation mark.
+    fn parse_lines(&self, raw_response: &str) -> Result<Vec<(u16, bool, String)>, Error> {
         let re = Regex::new(&self.m_regex).unwrap();
-        re.is_match(raw_response)
+        let mut lines = Vec::new();
+
+        for line in raw_response.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let caps = re.captures(line)
+                .ok_or_else(|| Error::SmtpResponse("Invalid response".to_string()))?;
+
+            let code = caps.get(1).unwrap().as_str().parse::<u16>().unwrap();
+            let is_last = caps.get(2).is_none_or(|separator| separator.as_str() == " ");
+            let text = caps.get(3).map_or("", |text| text.as_str()).to_string();
+
+            lines.push((code, is_last, text));
+        }
+
+        let Some((first_code, _, _)) = lines.first() else {
+            return Err(Error::SmtpResponse("Invalid response".to_string()));
+        };
+
+        if lines.iter().any(|(code, _, _)| code != first_code) {
+            return Err(Error::SmtpResponse("Inconsistent reply codes in multi-line response".to_string()));
+        }
+
+        let last_index = lines.len() - 1;
+        if lines.iter().enumerate().any(|(index, (_, is_last, _))| *is_last != (index == last_index)) {
+            return Err(Error::SmtpResponse("Malformed multi-line response".to_string()));
+        }
+
+        Ok(lines)
     }
 }
 
@@ -113,7 +160,7 @@ mod tests {
         let builder = SmtpResponseBuilder::new();
         let smtp_response = builder.build(response).unwrap();
         assert_eq!(smtp_response.m_status, SmtpStatus::PositiveCompletion);
-        assert_eq!(smtp_response.m_text, "OK");
+        assert_eq!(smtp_response.get_text(), "OK");
     }
 
     #[test]
@@ -124,19 +171,12 @@ mod tests {
         assert_eq!(status_code, 250);
     }
 
-    #[test]
-    fn test_parse_text() {
-        let response = "250 OK";
-        let builder = SmtpResponseBuilder::new();
-        let text = builder.parse_text(response).unwrap();
-        assert_eq!(text, "OK");
-    }
-    
     #[test]
     fn test_is_valid_response() {
         let response = "250 OK";
         let builder = SmtpResponseBuilder::new();
-        assert_eq!(builder.is_valid_response(response), true);
+        assert!(builder.build(response).is_ok());
+        assert!(builder.build("OK").is_err());
     }
 
     #[test]
@@ -163,10 +203,86 @@ mod tests {
                 250-ENHANCEDSTATUSCODES
                 250-PIPELINING
                 250-CHUNKING
-                130 SMTPUTF8";
+                250 SMTPUTF8";
         
         let builder = SmtpResponseBuilder::new();
         let smtp_response = builder.build(response).unwrap();
         assert_eq!(smtp_response.m_status, SmtpStatus::PositiveCompletion);
+        assert_eq!(smtp_response.get_text_lines().len(), 8);
+        assert_eq!(smtp_response.get_text_lines()[7], "SMTPUTF8");
+    }
+
+    #[test]
+    fn test_inconsistent_codes_response() {
+        let response = "250-smtp.example.com\r\n130 SMTPUTF8\r\n";
+        let builder = SmtpResponseBuilder::new();
+        assert_eq!(builder.build(response),
+            Err(Error::SmtpResponse("Inconsistent reply codes in multi-line response".to_string())));
+    }
+
+    #[test]
+    fn test_unterminated_multi_line_response() {
+        let response = "250-smtp.example.com\r\n250-SIZE 1000\r\n";
+        let builder = SmtpResponseBuilder::new();
+        assert_eq!(builder.build(response),
+            Err(Error::SmtpResponse("Malformed multi-line response".to_string())));
+    }
+
+    #[test]
+    fn test_code_only_line() {
+        let builder = SmtpResponseBuilder::new();
+        let smtp_response = builder.build("250\r\n").unwrap();
+        assert_eq!(smtp_response.m_status, SmtpStatus::PositiveCompletion);
+        assert_eq!(smtp_response.get_text(), "");
+    }
+
+    #[test]
+    fn test_enhanced_code() {
+        let response = "550-5.1.1 The email account that you tried to reach does not exist.\r\n550 5.1.1 Please try again.\r\n";
+        let builder = SmtpResponseBuilder::new();
+        let smtp_response = builder.build(response).unwrap();
+        assert_eq!(smtp_response.get_code(), 550);
+        assert_eq!(smtp_response.get_enhanced_code(), Some(EnhancedStatusCode::new(5, 1, 1)));
+        assert_eq!(smtp_response.get_enhanced_code().unwrap().description(), Some("Bad destination mailbox address"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_enhanced_code_class_mismatch_is_ignored() {
+        let builder = SmtpResponseBuilder::new();
+        let smtp_response = builder.build("452 5.2.2 Mailbox full\r\n").unwrap();
+        assert_eq!(smtp_response.get_code(), 452);
+        assert_eq!(smtp_response.get_enhanced_code(), None);
+
+        let smtp_response = builder.build("250 OK\r\n").unwrap();
+        assert_eq!(smtp_response.get_enhanced_code(), None);
+    }
+
+    #[test]
+    fn test_status_should_be() {
+        let builder = SmtpResponseBuilder::new();
+        let smtp_response = builder.build("452-4.2.2 The recipient's inbox is out of storage space.\r\n452 4.2.2 Please try again later.\r\n").unwrap();
+
+        assert_eq!(smtp_response.status_should_be(Some(&SmtpCommand::RcptTo), SmtpStatus::PositiveCompletion),
+            Err(Error::SmtpReply(SmtpReplyError {
+                command: Some(SmtpCommand::RcptTo),
+                expected: SmtpStatus::PositiveCompletion,
+                code: 452,
+                enhanced_code: Some(EnhancedStatusCode::new(4, 2, 2)),
+                text: "The recipient's inbox is out of storage space.\nPlease try again later.".to_string(),
+            })));
+        assert_eq!(smtp_response.status_should_be(None, SmtpStatus::TransientNegativeCompletion), Ok(()));
+
+        let error = smtp_response.status_should_be(Some(&SmtpCommand::RcptTo), SmtpStatus::PositiveCompletion).unwrap_err();
+        assert_eq!(error.to_string(), concat!(
+            "RCPT TO: failed: expected PositiveCompletion, got 452 4.2.2 (Mailbox full): ",
+            "The recipient's inbox is out of storage space.\nPlease try again later.\n"));
+    }
+
+    #[test]
+    fn test_get_text_lines() {
+        let response = "250-smtp.example.com\r\n250-SIZE 1000\r\n250 AUTH PLAIN LOGIN\r\n";
+        let builder = SmtpResponseBuilder::new();
+        let smtp_response = builder.build(response).unwrap();
+        assert_eq!(smtp_response.get_text_lines(), vec!["smtp.example.com", "SIZE 1000", "AUTH PLAIN LOGIN"]);
+    }
+}
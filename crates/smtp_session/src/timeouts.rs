@@ -0,0 +1,173 @@
+use std::fmt;
+
+use error_handler::Error;
+use tokio::time::Duration;
+
+use crate::SmtpCommand;
+
+/// The step of the SMTP dialogue a timeout applies to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpPhase {
+    Connect,
+    Greeting,
+    /// EHLO, STARTTLS, AUTH, QUIT and other commands without a timeout of
+    /// their own.
+    Command,
+    Mail,
+    Rcpt,
+    DataInitiation,
+    DataBlock,
+    DataTermination,
+}
+
+impl From<SmtpCommand> for SmtpPhase {
+    fn from(command: SmtpCommand) -> Self {
+        match command {
+            SmtpCommand::MailFrom => SmtpPhase::Mail,
+            SmtpCommand::RcptTo => SmtpPhase::Rcpt,
+            SmtpCommand::Data => SmtpPhase::DataInitiation,
+            SmtpCommand::Bdat => SmtpPhase::DataBlock,
+            SmtpCommand::Dot => SmtpPhase::DataTermination,
+            _ => SmtpPhase::Command,
+        }
+    }
+}
+
+impl fmt::Display for SmtpPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect => write!(f, "Connect"),
+            Self::Greeting => write!(f, "Greeting"),
+            Self::Command => write!(f, "Command"),
+            Self::Mail => write!(f, "MAIL"),
+            Self::Rcpt => write!(f, "RCPT"),
+            Self::DataInitiation => write!(f, "DATA initiation"),
+            Self::DataBlock => write!(f, "Data block"),
+            Self::DataTermination => write!(f, "DATA termination"),
+        }
+    }
+}
+
+/// How long the session waits in each phase before giving up with
+/// `Error::Timeout`.
+///
+/// The defaults are the client timeouts of RFC 5321 section 4.5.3.2.
+/// Connecting (including the TLS handshake on implicit TLS) is not covered
+/// by the RFC and defaults to 30 seconds; other commands get the 5 minutes
+/// used for MAIL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeouts {
+    m_connect: Duration,
+    m_greeting: Duration,
+    m_command: Duration,
+    m_mail: Duration,
+    m_rcpt: Duration,
+    m_data_initiation: Duration,
+    m_data_block: Duration,
+    m_data_termination: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            m_connect: Duration::from_secs(30),
+            m_greeting: Duration::from_secs(5 * 60),
+            m_command: Duration::from_secs(5 * 60),
+            m_mail: Duration::from_secs(5 * 60),
+            m_rcpt: Duration::from_secs(5 * 60),
+            m_data_initiation: Duration::from_secs(2 * 60),
+            m_data_block: Duration::from_secs(3 * 60),
+            m_data_termination: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn connect(mut self, timeout: Duration) -> Self {
+        self.m_connect = timeout;
+        self
+    }
+
+    pub fn greeting(mut self, timeout: Duration) -> Self {
+        self.m_greeting = timeout;
+        self
+    }
+
+    pub fn command(mut self, timeout: Duration) -> Self {
+        self.m_command = timeout;
+        self
+    }
+
+    pub fn mail(mut self, timeout: Duration) -> Self {
+        self.m_mail = timeout;
+        self
+    }
+
+    pub fn rcpt(mut self, timeout: Duration) -> Self {
+        self.m_rcpt = timeout;
+        self
+    }
+
+    pub fn data_initiation(mut self, timeout: Duration) -> Self {
+        self.m_data_initiation = timeout;
+        self
+    }
+
+    /// Limit for each write of the message content.
+    pub fn data_block(mut self, timeout: Duration) -> Self {
+        self.m_data_block = timeout;
+        self
+    }
+
+    /// Limit for the reply to the end of the message, the final "." or the
+    /// last BDAT chunk.
+    pub fn data_termination(mut self, timeout: Duration) -> Self {
+        self.m_data_termination = timeout;
+        self
+    }
+
+    pub fn get(&self, phase: SmtpPhase) -> Duration {
+        match phase {
+            SmtpPhase::Connect => self.m_connect,
+            SmtpPhase::Greeting => self.m_greeting,
+            SmtpPhase::Command => self.m_command,
+            SmtpPhase::Mail => self.m_mail,
+            SmtpPhase::Rcpt => self.m_rcpt,
+            SmtpPhase::DataInitiation => self.m_data_initiation,
+            SmtpPhase::DataBlock => self.m_data_block,
+            SmtpPhase::DataTermination => self.m_data_termination,
+        }
+    }
+
+    /// The error reported when `phase` ran out of time.
+    pub fn error(&self, phase: SmtpPhase) -> Error {
+        Error::Timeout(format!("{} timed out after {:?}", phase, self.get(phase)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc_5321_defaults() {
+        let timeouts = Timeouts::default();
+
+        assert_eq!(timeouts.get(SmtpPhase::Greeting), Duration::from_secs(300));
+        assert_eq!(timeouts.get(SmtpPhase::Mail), Duration::from_secs(300));
+        assert_eq!(timeouts.get(SmtpPhase::Rcpt), Duration::from_secs(300));
+        assert_eq!(timeouts.get(SmtpPhase::DataInitiation), Duration::from_secs(120));
+        assert_eq!(timeouts.get(SmtpPhase::DataBlock), Duration::from_secs(180));
+        assert_eq!(timeouts.get(SmtpPhase::DataTermination), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_builder_and_error() {
+        let timeouts = Timeouts::default().rcpt(Duration::from_millis(1500)).connect(Duration::from_secs(5));
+
+        assert_eq!(timeouts.get(SmtpPhase::Connect), Duration::from_secs(5));
+        assert_eq!(timeouts.error(SmtpPhase::Rcpt), Error::Timeout("RCPT timed out after 1.5s".to_string()));
+        assert_eq!(SmtpPhase::from(SmtpCommand::Dot), SmtpPhase::DataTermination);
+        assert_eq!(SmtpPhase::from(SmtpCommand::Ehlo), SmtpPhase::Command);
+    }
+}
@@ -8,7 +8,7 @@ use iced::Application;
 pub mod screen;
 use screen::{login, home};
 
-use smtp_session::{self, SmtpMessage, SmtpSession};
+use smtp_session::{self, ConnectionSecurity, Credentials, SmtpMessage, SmtpSession, TlsConfig};
 use home::HomeMessage;
 use login::LoginMessage;
 use error_handler::Error;
@@ -114,7 +114,7 @@ impl Application for App {
         iced::Command::none()
     }
 
-    fn view(&self) -> Element<Self::Message> {
+    fn view(&self) -> Element<'_, Self::Message> {
        let screen = match &self.screen {
             Screen::LoginPage(pageone) => pageone.view().map(Message::LoginMsg),
             Screen::HomePage(pagetwo) => pagetwo.view().map(Message::HomeMsg),
@@ -139,19 +139,25 @@ impl App {
             {
                 let mut session = session.lock().await;
 
-                if let Ok(mut smtp_session) = SmtpSession::connect(&server).await {
-                    smtp_session.encrypt_connection().await?;
+                // Port 465 expects implicit TLS, every other port has to offer STARTTLS.
+                let security = if server.ends_with(":465") {
+                    ConnectionSecurity::Implicit
+                } else {
+                    ConnectionSecurity::StartTlsRequired
+                };
+
+                if let Ok(mut smtp_session) = SmtpSession::connect(&server, security, TlsConfig::default()).await {
                     let result = match state {
-                        screen::login::State::Login => smtp_session.authenticate(&login, &password).await,
-                        screen::login::State::Register => smtp_session.register(&login, &password).await,
+                        screen::login::State::Login => smtp_session.authenticate_auto(&Credentials::password(&login, &password)).await.map(|_| ()),
+                        screen::login::State::Register => smtp_session.register(&login, &password).await.map(|_| ()),
                     };
                     *session = Some(smtp_session);
-                    return result;
+                    result
                     
                 }
                 else {
                     *session = None;
-                    return Err(Error::SmtpResponse("Connection failed".to_string()));
+                    Err(Error::SmtpResponse("Connection failed".to_string()))
                 }
                 
             }),
@@ -164,17 +170,17 @@ impl App {
                         Err(e) => {
                             match e {
                                 Error::SmtpResponse(e) => {
-                                    return Message::LoginMsg(LoginMessage::UpdateInfoMessage(format!("Error: \n{}", e).to_string()));
+                                    Message::LoginMsg(LoginMessage::UpdateInfoMessage(format!("Error: \n{}", e).to_string()))
                                 },
                                 _ => {
-                                    return Message::LoginMsg(LoginMessage::UpdateInfoMessage(format!("Error: \r\n{}", e).to_string()));
+                                    Message::LoginMsg(LoginMessage::UpdateInfoMessage(format!("Error: \r\n{}", e).to_string()))
                                 }
                             }
                         }
                     }
                 }
                 else {
-                    return Message::LoginMsg(LoginMessage::UpdateInfoMessage("Connection failed".to_string()));
+                    Message::LoginMsg(LoginMessage::UpdateInfoMessage("Connection failed".to_string()))
                 }
             }
         )
@@ -25,10 +25,10 @@ pub struct Home {
 
 impl Home {
     pub fn new() -> Self {
-        let mut home = Home::default();
-        home.page_description = "SMTP Client".to_string();
-
-        home
+        Home {
+            page_description: "SMTP Client".to_string(),
+            ..Default::default()
+        }
     }
 
     pub fn update(&mut self, message: HomeMessage) {
@@ -55,8 +55,8 @@ impl Home {
             column![
                 Text::new(self.page_description.clone()).size(25),
 
-                TextInput::new("Recipient", &self.recipient).on_input(|recipient| HomeMessage::UpdateRecipient(recipient)),
-                TextInput::new("Subject", &self.subject).on_input(|subject| HomeMessage::UpdateSubject(subject)),
+                TextInput::new("Recipient", &self.recipient).on_input(HomeMessage::UpdateRecipient),
+                TextInput::new("Subject", &self.subject).on_input(HomeMessage::UpdateSubject),
 
                 column![
                     row![
@@ -65,7 +65,7 @@ impl Home {
                     
                     TextEditor::new(&self.message)
                         .height(Length::from(200))
-                        .on_action(|action| HomeMessage::UpdateMessage(action)),
+                        .on_action(HomeMessage::UpdateMessage),
                 ].padding(Padding::from([4, 0, 0, 0])),
 
                 row![
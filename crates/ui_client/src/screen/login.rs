@@ -77,9 +77,9 @@ impl Login {
             }).size(25),
 
             // input fields
-            TextInput::new("smtp.gmail.com:587", &self.server).on_input(|server| LoginMessage::UpdateServer(server)),
-            TextInput::new("user@gmail.com", &self.login).on_input(|login| LoginMessage::UpdateLogin(login)),
-            TextInput::new("password", &self.password).on_input(|password| LoginMessage::UpdatePassword(password)),
+            TextInput::new("smtp.gmail.com:587", &self.server).on_input(LoginMessage::UpdateServer),
+            TextInput::new("user@gmail.com", &self.login).on_input(LoginMessage::UpdateLogin),
+            TextInput::new("password", &self.password).on_input(LoginMessage::UpdatePassword),
 
             // row with buttons to change the state and to move to the next page
             row![